@@ -0,0 +1,461 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+    time::Duration,
+};
+
+use crate::{
+    cache::Cache,
+    deps::{DependencyGraph, Plan},
+    discover,
+    error::Result,
+    log,
+    state::{self, BuildState},
+    target::{Config, Target, CONFIG_FILE},
+    util::{self, RunOptions},
+};
+
+/// A loaded project - its config, dependency graph and build state.
+///
+/// Paths in the config are relative to the current directory,
+/// just like when running the `dodo` binary.
+pub struct Build {
+    config: Config,
+    graph: DependencyGraph,
+    state: BuildState,
+    options: RunOptions,
+}
+
+impl Build {
+    /// Loads `dodo.toml` and `dodo.lock` from the current directory.
+    pub fn load(options: RunOptions) -> Result<Self> {
+        let config = util::load_config(CONFIG_FILE)?;
+
+        Self::new(config, state::LOCK_FILE, options)
+    }
+
+    /// Creates a build of the targets in `config`. The state of the build
+    /// is loaded from (and saved to) `lock_file`, the dependencies found
+    /// by the last discoveries are taken from it. The hash algorithm
+    /// and the staleness strategy in `options` are overridden
+    /// with the ones from the config.
+    pub fn new(
+        config: Config,
+        lock_file: impl AsRef<Path>,
+        options: RunOptions,
+    ) -> Result<Self> {
+        let state = BuildState::load(lock_file, config.hash, config.staleness)?;
+        let graph = DependencyGraph::construct(targets(&config, &state))?;
+        let options = RunOptions {
+            hash: config.hash,
+            staleness: config.staleness,
+            ..options
+        };
+
+        Ok(Self {
+            config,
+            graph,
+            state,
+            options,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn graph(&self) -> &DependencyGraph {
+        &self.graph
+    }
+
+    pub fn state(&self) -> &BuildState {
+        &self.state
+    }
+
+    pub fn options(&self) -> &RunOptions {
+        &self.options
+    }
+
+    /// Returns the targets which have to be built to bring the target
+    /// up to date.
+    pub fn plan(&self, target: impl Into<PathBuf>) -> Result<Plan> {
+        self.graph.get_plan(target.into(), &self.state)
+    }
+
+    /// Returns the chain of targets which took the longest to build
+    /// the last time (see [`DependencyGraph::get_critical_path`]).
+    pub fn critical_path(
+        &self,
+        target: impl Into<PathBuf>,
+    ) -> Result<Vec<(PathBuf, Option<Duration>)>> {
+        self.graph.get_critical_path(target.into(), &self.state)
+    }
+
+    /// Returns the target and all the targets it depends on,
+    /// or all targets if none is chosen.
+    pub fn targets(&self, target: Option<PathBuf>) -> Result<Vec<&Target>> {
+        self.graph.get_targets(target)
+    }
+
+    /// Builds the obsolete targets the target depends on (including
+    /// the target itself) and saves the build state. The build stops
+    /// once `cancel` gets set.
+    pub fn run(
+        &mut self,
+        target: impl Into<PathBuf>,
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        let plan = self.plan(target)?;
+
+        self.run_plan(plan, cancel)
+    }
+
+    /// Executes a plan returned by [`Build::plan`]. The discover tasks
+    /// of the planned targets run first if their inputs have changed
+    /// (once the inputs which are targets have been built), then
+    /// the target is planned again with the dependencies they print.
+    pub fn run_plan(
+        &mut self,
+        mut plan: Plan,
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        // targets built along with discover inputs, they aren't rebuilt
+        // even if they're always obsolete
+        let mut built = HashSet::new();
+        loop {
+            let planned = plan
+                .targets
+                .iter()
+                .map(|(target, _)| &target.identifier)
+                .chain(&plan.up_to_date)
+                .cloned()
+                .collect::<HashSet<_>>();
+            // the inputs are the dependencies of the discovering targets
+            // in the graph, so they can't depend on them in turn
+            let mut inputs_built = false;
+            for input in discover_inputs(&self.config, &planned) {
+                let mut input_plan = self.plan(input)?;
+                input_plan
+                    .targets
+                    .retain(|(target, _)| !built.contains(&target.identifier));
+                if input_plan.targets.is_empty() {
+                    continue;
+                }
+
+                built.extend(
+                    input_plan
+                        .targets
+                        .iter()
+                        .map(|(target, _)| target.identifier.clone()),
+                );
+                self.run_plan(input_plan, cancel)?;
+                inputs_built = true;
+            }
+
+            let mut targets = targets(&self.config, &self.state);
+            let discovered = discover::discover_deps(
+                &mut targets,
+                |target| planned.contains(target),
+                &mut self.state,
+                &self.options,
+                cancel,
+            )?;
+            if discovered {
+                self.state.save()?;
+                self.graph = DependencyGraph::construct(targets)?;
+            }
+            if !inputs_built && !discovered {
+                break;
+            }
+
+            plan = self.plan(plan.target)?;
+        }
+
+        let (targets, rebuilt) = plan
+            .targets
+            .into_iter()
+            .partition(|(target, _)| !built.contains(&target.identifier));
+        plan.targets = targets;
+        plan.up_to_date
+            .extend(rebuilt.into_iter().map(|(target, _)| target.identifier));
+
+        // opened only now so that querying the graph doesn't require
+        // the cache to be available
+        let options = RunOptions {
+            cache: Cache::open(&self.config.cache, self.config.hash)?,
+            ..self.options.clone()
+        };
+
+        util::run_targets(plan, &mut self.state, &options, cancel)
+    }
+
+    /// Removes the outputs and the logs of the target and all the targets
+    /// it depends on and forgets their state. If no target is chosen,
+    /// all targets are cleaned and the log dir is removed.
+    pub fn clean(
+        &mut self,
+        target: Option<PathBuf>,
+        dry_run: bool,
+    ) -> Result<()> {
+        let log_dir = &self.options.log_dir;
+        let all = target.is_none();
+        let targets = self.graph.get_targets(target)?;
+        let others = match all {
+            true => vec![log_dir.clone()],
+            false => targets
+                .iter()
+                .flat_map(|target| {
+                    let identifier = &target.identifier;
+                    vec![
+                        log::log_path(log_dir, identifier),
+                        log::log_path(log_dir, &discover::log_name(identifier)),
+                    ]
+                })
+                .collect(),
+        };
+
+        util::clean_targets(&targets, &others, &mut self.state, dry_run)
+    }
+}
+
+/// Returns the inputs of the planned targets' discover tasks which
+/// are targets themselves.
+fn discover_inputs(
+    config: &Config,
+    planned: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut inputs = config
+        .targets
+        .iter()
+        .filter(|target| planned.contains(&target.identifier))
+        .filter_map(|target| target.discover.as_ref())
+        .flat_map(|discover| &discover.deps)
+        .filter(|dep| {
+            config
+                .targets
+                .iter()
+                .any(|target| target.identifier == **dep)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    inputs.sort();
+    inputs.dedup();
+
+    inputs
+}
+
+/// Returns the targets with the dependencies recorded in the state.
+fn targets(config: &Config, state: &BuildState) -> Vec<Target> {
+    let mut targets = config.targets.clone();
+    state.add_discovered_deps(&mut targets);
+    discover::add_recorded_deps(&mut targets, state);
+
+    targets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, sync::Arc};
+
+    use crate::{
+        error::{Error, UserError},
+        executor::MockExecutor,
+        log::OutputMode,
+        report::SilentReporter,
+        target::{Discover, Task},
+        test_util::TempDir,
+    };
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_dry_run() {
+        let dir = TempDir::new("dry");
+        let lock_file = dir.join("dodo.lock");
+        let executor = Arc::new(MockExecutor::default());
+        let config = Config {
+            targets: vec![Target {
+                identifier: dir.join("out"),
+                discover: Some(Discover {
+                    command: "list".into(),
+                    deps: vec![],
+                }),
+                tasks: vec![Task {
+                    command: "generate".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            hash: Default::default(),
+            staleness: Default::default(),
+            cache: Default::default(),
+        };
+        let options = RunOptions {
+            reporter: Arc::new(SilentReporter),
+            executor: executor.clone(),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        let mut build = Build::new(config, &lock_file, options).unwrap();
+        let plan = build.plan(dir.join("out")).unwrap();
+        let written = lock_file.exists() || dir.join("logs").exists();
+        let planned_tasks = executor.tasks().len();
+
+        // the discovery runs before the target once the plan is executed
+        let result = build.run_plan(plan, &AtomicBool::new(false));
+        let saved = lock_file.exists();
+
+        // neither the task nor the discovery has been run
+        assert_eq!(planned_tasks, 0);
+        assert!(!written);
+
+        result.unwrap();
+        let argvs = executor
+            .tasks()
+            .into_iter()
+            .map(|task| task.argv.join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(argvs, vec!["list", "generate"]);
+        assert!(saved);
+    }
+
+    #[test]
+    fn test_env() {
+        let dir = TempDir::new("env");
+        let script = dir.join("env.sh");
+        fs::write(&script, "printf '%s %s' \"$A\" \"$B\" > \"$1\"").unwrap();
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let config = Config {
+            targets: vec![Target {
+                identifier: dir.join("out"),
+                env: env(&[("A", "target"), ("B", "target")]),
+                tasks: vec![Task {
+                    command: format!("sh {} {{target}}", script.display()),
+                    env: env(&[("B", "task")]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            hash: Default::default(),
+            staleness: Default::default(),
+            cache: Default::default(),
+        };
+        let options = RunOptions {
+            output: OutputMode::Quiet,
+            reporter: Arc::new(SilentReporter),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        Build::new(config, dir.join("dodo.lock"), options)
+            .and_then(|mut build| {
+                build.run(dir.join("out"), &AtomicBool::new(false))
+            })
+            .unwrap();
+
+        // task's env overrides target's
+        let output = fs::read_to_string(dir.join("out")).unwrap();
+        assert_eq!(output, "target task");
+    }
+
+    #[test]
+    fn test_generated_discover_inputs() {
+        let dir = TempDir::new("generated");
+        let headers = dir.join("headers");
+        let header = dir.join("a.h");
+        fs::write(&header, "").unwrap();
+        let executor = Arc::new(MockExecutor::with_output({
+            let (headers, header) = (headers.clone(), header.clone());
+            move |task| match task.argv[0].as_str() {
+                "generate" => {
+                    fs::write(&headers, header.to_string_lossy().as_bytes())
+                        .unwrap();
+                    (0, String::new())
+                }
+                // prints nothing unless the list has been generated
+                "list" => (0, fs::read_to_string(&headers).unwrap_or_default()),
+                _ => {
+                    fs::write(&task.argv[1], "").unwrap();
+                    (0, String::new())
+                }
+            }
+        }));
+        let target = |identifier: PathBuf, command: &str| Target {
+            identifier,
+            tasks: vec![Task {
+                command: command.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = |targets| Config {
+            targets,
+            hash: Default::default(),
+            staleness: Default::default(),
+            cache: Default::default(),
+        };
+        let app = Target {
+            discover: Some(Discover {
+                command: "list".into(),
+                deps: vec![headers.clone()],
+            }),
+            ..target(dir.join("app"), "compile {target}")
+        };
+        let options = RunOptions {
+            reporter: Arc::new(SilentReporter),
+            executor: executor.clone(),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        // the list is generated before the discover task reads it
+        let mut build = Build::new(
+            config(vec![app.clone(), target(headers.clone(), "generate")]),
+            dir.join("dodo.lock"),
+            options.clone(),
+        )
+        .unwrap();
+        build.run(dir.join("app"), &AtomicBool::new(false)).unwrap();
+        let argvs = || {
+            executor
+                .tasks()
+                .into_iter()
+                .map(|task| task.argv[0].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(argvs(), vec!["generate", "list", "compile"]);
+        let targets = build.targets(Some(dir.join("app"))).unwrap();
+        let app_deps = targets
+            .iter()
+            .find(|target| target.identifier == dir.join("app"))
+            .map(|target| target.dynamic_deps.clone());
+        assert_eq!(app_deps, Some(vec![header]));
+
+        // the list is always obsolete but it's generated once per build,
+        // the discovery isn't re-run while the list stays the same
+        build.run(dir.join("app"), &AtomicBool::new(false)).unwrap();
+        assert_eq!(argvs()[3..].to_vec(), vec!["generate"]);
+
+        // discover inputs can't depend on the discovering target
+        let cyclic = config(vec![
+            app,
+            Target {
+                deps: vec![dir.join("app")],
+                ..target(headers, "generate")
+            },
+        ]);
+        let cycle = Build::new(cyclic, dir.join("dodo.lock"), options);
+        assert!(matches!(
+            cycle.err(),
+            Some(Error::UserError(UserError::DependencyCycle))
+        ));
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    iter::once,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    error::{Error, Result, UserError},
+    hash::{self, HashAlgorithm},
+    http::{self, Url},
+    progress,
+    target::{Target, TaskContext},
+};
+
+use ansi_term::Colour::Yellow;
+use serde::*;
+
+/// Bumped whenever the way keys are computed changes.
+const CACHE_VERSION: u32 = 3;
+
+/// Overrides `remote` of the `[cache]` section.
+const REMOTE_CACHE_VAR: &str = "DODO_REMOTE_CACHE";
+/// Overrides `remote_mode` of the `[cache]` section.
+const REMOTE_CACHE_MODE_VAR: &str = "DODO_REMOTE_CACHE_MODE";
+
+/// The `[cache]` section of the config.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CacheConfig {
+    /// Whether outputs are stored in (and restored from) the local cache.
+    #[serde(default)]
+    pub local: bool,
+    /// Defaults to `$XDG_CACHE_HOME/dodo` or `~/.cache/dodo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// URL of the remote cache, e.g. `http://cache.local:8080/dodo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    #[serde(default)]
+    pub remote_mode: RemoteMode,
+}
+
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq, Default,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteMode {
+    /// Outputs are only downloaded (e.g. on developers' machines).
+    #[default]
+    Read,
+    /// Outputs are downloaded and uploaded (e.g. on CI).
+    ReadWrite,
+}
+
+impl RemoteMode {
+    fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "read" => Some(RemoteMode::Read),
+            "read-write" => Some(RemoteMode::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// Outputs of targets stored under their keys in a local directory
+/// and/or on an HTTP server (both use the same `<xx>/<key>` layout
+/// so a local cache can be served by any static file server).
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: Option<PathBuf>,
+    remote: Option<(Url, RemoteMode)>,
+    hash: HashAlgorithm,
+}
+
+impl Cache {
+    /// Returns the cache if any of its backends is enabled in the config
+    /// (or the remote one in the environment).
+    pub fn open(
+        config: &CacheConfig,
+        hash: HashAlgorithm,
+    ) -> Result<Option<Self>> {
+        let dir = match (&config.dir, config.local) {
+            (_, false) => None,
+            (Some(dir), true) => Some(dir.clone()),
+            (None, true) => Some(default_dir().ok_or(UserError::NoCacheDir)?),
+        };
+
+        let url = env::var(REMOTE_CACHE_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())
+            .or_else(|| config.remote.clone());
+        let mode = match env::var(REMOTE_CACHE_MODE_VAR) {
+            Ok(mode) => RemoteMode::parse(&mode).ok_or_else(|| {
+                UserError::InvalidValue {
+                    option: REMOTE_CACHE_MODE_VAR.to_string(),
+                    value: mode,
+                }
+            })?,
+            Err(_) => config.remote_mode,
+        };
+        let remote = match url {
+            Some(url) => Some((Url::parse(&url)?, mode)),
+            None => None,
+        };
+
+        if dir.is_none() && remote.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self { dir, remote, hash }))
+    }
+
+    /// Computes the key of target's output from the hashes of its dependencies
+    /// and its tasks (commands, working dirs and env). Targets with no
+    /// dependencies (which are assumed to depend on other factors) and targets
+    /// depending on something else than files can't be cached.
+    pub fn key(
+        &self,
+        target: &Target,
+        context: &TaskContext,
+    ) -> Result<Option<String>> {
+        let deps = target
+            .deps
+            .iter()
+            .chain(&target.discovered_deps)
+            .chain(&target.dynamic_deps)
+            .collect::<Vec<_>>();
+
+        if target.deps.is_empty() || deps.iter().any(|dep| !dep.is_file()) {
+            return Ok(None);
+        }
+
+        let mut hasher = KeyHasher(hash::Hasher::new(self.hash));
+
+        hasher.u32(CACHE_VERSION);
+        hasher.path(&target.identifier);
+        // working dirs are relative to the project dir (if at all)
+        // so the key doesn't depend on where the project is
+        hasher.optional_path(target.working_dir());
+
+        hasher.len(deps.len());
+        for dep in deps {
+            hasher.path(dep);
+            hasher.str(&hash::hash_file(dep, self.hash)?);
+        }
+
+        hasher.len(target.tasks.len());
+        for task in &target.tasks {
+            let (command, args) = task.format_command(context)?;
+            hasher.len(args.len() + 1);
+            for arg in once(command).chain(args) {
+                hasher.str(&arg);
+            }
+            hasher.optional_path(task.working_dir());
+
+            let env = task.resolve_env(&target.env);
+            hasher.len(env.len());
+            for (name, value) in &env {
+                hasher.str(name);
+                hasher.str(value);
+            }
+        }
+
+        Ok(Some(hasher.0.digest()))
+    }
+
+    /// Restores the output from the cache, trying the local cache first.
+    /// Returns whether any of the backends contained it. Failures of the
+    /// remote cache are reported and treated as misses.
+    pub fn restore(&self, key: &str, output: &Path) -> Result<bool> {
+        if let Some(dir) = &self.dir {
+            let entry = dir.join(entry_path(key));
+
+            if entry.is_file() {
+                copy_atomically(&entry, output)?;
+
+                return Ok(true);
+            }
+        }
+
+        if let Some((url, _)) = &self.remote {
+            let url = url.join(&entry_path(key));
+            let downloaded = write_atomically(output, |file| {
+                http::get(&url, file).map(|found| found.then_some(()))
+            });
+
+            match downloaded {
+                Ok(Some(())) => {
+                    // keep a local copy for the next time
+                    if let Some(dir) = &self.dir {
+                        copy_atomically(output, &dir.join(entry_path(key)))?;
+                    }
+
+                    return Ok(true);
+                }
+                Ok(None) => {}
+                Err(err) => warn_remote(&err),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Stores the output in the cache (in the remote one only in the
+    /// read-write mode). Outputs which are not files (e.g. directories
+    /// or targets without output) are skipped.
+    pub fn store(&self, key: &str, output: &Path) -> Result<()> {
+        if !output.is_file() {
+            return Ok(());
+        }
+
+        if let Some(dir) = &self.dir {
+            copy_atomically(output, &dir.join(entry_path(key)))?;
+        }
+
+        if let Some((url, RemoteMode::ReadWrite)) = &self.remote {
+            let url = url.join(&entry_path(key));
+            let uploaded =
+                File::open(output).map_err(Error::IO).and_then(|mut file| {
+                    let len = file.metadata().map_err(Error::IO)?.len();
+
+                    http::put(&url, &mut file, len)
+                });
+
+            if let Err(err) = uploaded {
+                warn_remote(&err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Feeds the inputs of a key to the hasher in an encoding which doesn't
+/// depend on the platform: integers are little-endian, strings are
+/// prefixed with their length as `u64` and paths are encoded as their
+/// components joined with `/`.
+struct KeyHasher(hash::Hasher);
+
+impl KeyHasher {
+    fn u32(&mut self, value: u32) {
+        self.0.update(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.0.update(&(len as u64).to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.0.update(value.as_bytes());
+    }
+
+    fn path(&mut self, path: &Path) {
+        let components = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>();
+
+        self.str(&components.join("/"));
+    }
+
+    fn optional_path(&mut self, path: Option<&Path>) {
+        match path {
+            Some(path) => {
+                self.0.update(&[1]);
+                self.path(path);
+            }
+            None => self.0.update(&[0]),
+        }
+    }
+}
+
+fn entry_path(key: &str) -> String {
+    format!("{}/{}", &key[..2], key)
+}
+
+fn warn_remote(err: &Error) {
+    progress::eprintln(&format!(
+        "{}: remote cache: {}",
+        Yellow.paint("WARNING"),
+        err
+    ));
+}
+
+fn default_dir() -> Option<PathBuf> {
+    let cache_dir = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| Path::new(&home).join(".cache"))
+        })?;
+
+    Some(cache_dir.join("dodo"))
+}
+
+/// Copies the file through a temporary file so that a partially
+/// copied file never ends up in the destination.
+fn copy_atomically(from: &Path, to: &Path) -> Result<()> {
+    write_atomically(to, |tmp_file| {
+        // `fs::copy` preserves the permissions, `io::copy` doesn't
+        let tmp_path = tmp_file.path.clone();
+
+        fs::copy(from, tmp_path).map(Some).map_err(Error::IO)
+    })
+    .map(|_| ())
+}
+
+struct TmpFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl Write for TmpFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the file through a temporary file which is renamed
+/// to `to` only if `write` succeeds and returns `Some`.
+fn write_atomically<T>(
+    to: &Path,
+    write: impl FnOnce(&mut TmpFile) -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    if let Some(dir) = to.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(Error::IO)?;
+    }
+
+    let mut tmp_path = OsString::from(to);
+    tmp_path.push(format!(".{}.tmp", process::id()));
+    let path = PathBuf::from(tmp_path);
+    let file = File::create(&path).map_err(Error::IO)?;
+    let mut tmp_file = TmpFile { path, file };
+
+    let result = write(&mut tmp_file);
+    let result = match result {
+        Ok(Some(value)) => fs::rename(&tmp_file.path, to)
+            .map(|()| Some(value))
+            .map_err(Error::IO),
+        other => other,
+    };
+
+    if !matches!(result, Ok(Some(_))) {
+        let _ = fs::remove_file(&tmp_file.path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use crate::test_util::TempDir;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A stand-in for a directory-serving HTTP server.
+    fn serve() -> (Url, Files) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        let files = Files::default();
+        let served = files.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let mut request = line.split_whitespace();
+                let method = request.next().unwrap().to_string();
+                let path = request.next().unwrap().to_string();
+
+                let mut len = 0;
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length:") {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+
+                let mut files = served.lock().unwrap();
+                let response = match (method.as_str(), files.get(&path)) {
+                    ("GET", Some(file)) => {
+                        let mut response = format!(
+                            "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n",
+                            file.len()
+                        )
+                        .into_bytes();
+                        response.extend(file);
+                        response
+                    }
+                    ("GET", None) => b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec(),
+                    _ => {
+                        files.insert(path, body);
+                        b"HTTP/1.0 201 Created\r\n\r\n".to_vec()
+                    }
+                };
+
+                stream.get_mut().write_all(&response).unwrap();
+            }
+        });
+
+        (Url::parse(&url).unwrap(), files)
+    }
+
+    #[test]
+    fn test_local_cache() {
+        let dir = TempDir::new("local");
+        let output = dir.join("out");
+        let key = "fedcba9876543210";
+        let cache = Cache {
+            dir: Some(dir.join("cache")),
+            remote: None,
+            hash: HashAlgorithm::default(),
+        };
+
+        fs::write(&output, "v1").unwrap();
+        cache.store(key, &output).unwrap();
+        let stored = fs::read(dir.join("cache/fe").join(key)).ok();
+        fs::remove_file(&output).unwrap();
+        let restored = cache.restore(key, &output).unwrap();
+        let content = fs::read(&output).ok();
+        let missed = cache.restore("0123456789abcdef", &output).unwrap();
+
+        assert_eq!(stored, Some(b"v1".to_vec()));
+        assert!(restored);
+        assert_eq!(content, Some(b"v1".to_vec()));
+        assert!(!missed);
+    }
+
+    #[test]
+    fn test_key() {
+        let dir = TempDir::new("key");
+        let dep = dir.join("a.c");
+        let cache = Cache {
+            dir: None,
+            remote: None,
+            hash: HashAlgorithm::default(),
+        };
+        let target = Target {
+            identifier: "a.o".into(),
+            deps: vec![dep.clone()],
+            tasks: vec![crate::target::Task {
+                command: "cc -c {target}".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let key = |target: &Target| {
+            cache.key(target, &target.task_context().unwrap()).unwrap()
+        };
+
+        fs::write(&dep, "int a;").unwrap();
+        let keys = (key(&target), key(&target));
+        let mut changed_command = target.clone();
+        changed_command.tasks[0].command = "cc -O2 -c {target}".into();
+        let mut changed_env = target.clone();
+        changed_env.env.insert("CC".into(), "clang".into());
+        let changed = [key(&changed_command), key(&changed_env)];
+        fs::write(&dep, "int b;").unwrap();
+        let changed_dep = key(&target);
+        let no_deps = key(&Target {
+            deps: vec![],
+            ..target.clone()
+        });
+
+        let (key, same_key) = keys;
+        assert!(key.is_some());
+        assert_eq!(key, same_key);
+        assert!(changed.iter().all(|changed| *changed != key));
+        assert!(changed[0] != changed[1]);
+        assert!(changed_dep.is_some() && changed_dep != key);
+        assert_eq!(no_deps, None);
+    }
+
+    #[test]
+    fn test_remote_cache() {
+        let (url, files) = serve();
+        let dir = TempDir::new("remote");
+        let output = dir.join("out");
+        let key = "0123456789abcdef";
+
+        let cache = |mode| Cache {
+            dir: None,
+            remote: Some((url.clone(), mode)),
+            hash: HashAlgorithm::default(),
+        };
+
+        // read-only caches don't upload
+        fs::write(&output, "v1").unwrap();
+        cache(RemoteMode::Read).store(key, &output).unwrap();
+        assert!(files.lock().unwrap().is_empty());
+        assert!(!cache(RemoteMode::Read).restore(key, &output).unwrap());
+
+        cache(RemoteMode::ReadWrite).store(key, &output).unwrap();
+        assert_eq!(
+            files.lock().unwrap().get("/cache/01/0123456789abcdef"),
+            Some(&b"v1".to_vec())
+        );
+
+        fs::remove_file(&output).unwrap();
+        assert!(cache(RemoteMode::Read).restore(key, &output).unwrap());
+        assert_eq!(fs::read(&output).unwrap(), b"v1");
+    }
+}
@@ -0,0 +1,279 @@
+use std::{path::PathBuf, time::Duration};
+
+use dodo::{
+    parse_duration,
+    MessageFormat,
+    OutputMode,
+    Result,
+    Schedule,
+    TraceDeps,
+    UserError,
+};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    Build {
+        target: PathBuf,
+    },
+    /// Rebuild the target whenever its inputs change.
+    Watch {
+        target: PathBuf,
+    },
+    /// Print the chain of targets which took the longest to build.
+    CriticalPath {
+        target: PathBuf,
+    },
+    /// Remove the outputs of the target and its dependencies
+    /// (or of all targets).
+    Clean {
+        target: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    /// Print the plan without executing anything.
+    pub dry_run: bool,
+    /// Kill running tasks when inputs change in watch mode.
+    pub restart: bool,
+    /// Timeout for tasks which don't specify one.
+    pub timeout: Option<Duration>,
+    /// How tasks' output is echoed.
+    pub output: OutputMode,
+    /// How many targets can be built at the same time.
+    pub jobs: usize,
+    /// Print all the targets of the config before building.
+    pub verbose: bool,
+    pub message_format: MessageFormat,
+    /// Where to write the trace of the build.
+    pub profile: Option<PathBuf>,
+    /// Which of the ready targets are started first.
+    pub schedule: Schedule,
+    /// Whether tasks are traced to find undeclared inputs and outputs.
+    pub trace_deps: TraceDeps,
+}
+
+impl Args {
+    /// Parses command line arguments (without the program name).
+    /// Arguments following `--` are never options nor commands, so that
+    /// e.g. `dodo -- clean` builds the target named `clean`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut positional = vec![];
+        // how many positional arguments precede `--`
+        let mut separator = None;
+        let mut dry_run = false;
+        let mut restart = false;
+        let mut timeout = None;
+        let mut output = OutputMode::Raw;
+        let mut jobs = 1;
+        let mut verbose = false;
+        let mut message_format = MessageFormat::Human;
+        let mut profile = None;
+        let mut schedule = Schedule::Plan;
+        let mut trace_deps = TraceDeps::Off;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if separator.is_some() {
+                positional.push(arg);
+                continue;
+            }
+            if arg == "--" {
+                separator = Some(positional.len());
+                continue;
+            }
+
+            // split `--option=value` and `-j4`
+            let (option, mut inline_value) = match arg.find('=') {
+                Some(ix) if arg.starts_with("--") =>
+                    (arg[..ix].to_string(), Some(arg[ix + 1..].to_string())),
+                _ if arg.starts_with("-j") && arg.len() > 2 =>
+                    ("-j".to_string(), Some(arg[2..].to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .take()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| UserError::MissingValue(option.clone()))
+            };
+            let invalid = |value: String| UserError::InvalidValue {
+                option: option.clone(),
+                value,
+            };
+
+            match option.as_str() {
+                "-n" | "--dry-run" => dry_run = true,
+                "--restart" => restart = true,
+                "-v" | "--verbose" => verbose = true,
+                "-q" | "--quiet" => output = OutputMode::Quiet,
+                "--timeout" => {
+                    let value = value()?;
+                    let duration =
+                        parse_duration(&value).ok_or_else(|| invalid(value))?;
+                    timeout = Some(duration);
+                }
+                "--output" => {
+                    let value = value()?;
+                    output = match value.as_str() {
+                        "raw" => OutputMode::Raw,
+                        "prefixed" => OutputMode::Prefixed,
+                        "grouped" => OutputMode::Grouped,
+                        "quiet" => OutputMode::Quiet,
+                        _ => return Err(invalid(value).into()),
+                    };
+                }
+                "--message-format" => {
+                    let value = value()?;
+                    message_format = match value.as_str() {
+                        "human" => MessageFormat::Human,
+                        "json" => MessageFormat::Json,
+                        _ => return Err(invalid(value).into()),
+                    };
+                }
+                "--profile" => profile = Some(PathBuf::from(value()?)),
+                "--schedule" => {
+                    let value = value()?;
+                    schedule = match value.as_str() {
+                        "plan" => Schedule::Plan,
+                        "critical-path" => Schedule::CriticalPath,
+                        _ => return Err(invalid(value).into()),
+                    };
+                }
+                // the value is optional so it has to be inline
+                "--trace-deps" => {
+                    trace_deps = match inline_value.take().as_deref() {
+                        None | Some("report") => TraceDeps::Report,
+                        Some("record") => TraceDeps::Record,
+                        Some(value) => return Err(invalid(value.into()).into()),
+                    };
+                }
+                "-j" | "--jobs" => {
+                    let value = value()?;
+                    jobs = match value.parse::<usize>() {
+                        Ok(jobs) if jobs > 0 => jobs,
+                        _ => return Err(invalid(value).into()),
+                    };
+                }
+                _ if arg.starts_with('-') =>
+                    return Err(UserError::UnknownOption(arg).into()),
+                _ => positional.push(arg),
+            }
+        }
+
+        let is_command = separator != Some(0);
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            Some("watch") if is_command => Command::Watch {
+                target: positional
+                    .next()
+                    .ok_or(UserError::NoTargetSpecified)?
+                    .into(),
+            },
+            Some("critical-path") if is_command => Command::CriticalPath {
+                target: positional
+                    .next()
+                    .ok_or(UserError::NoTargetSpecified)?
+                    .into(),
+            },
+            Some("clean") if is_command => Command::Clean {
+                target: positional.next().map(PathBuf::from),
+            },
+            Some(target) => Command::Build {
+                target: target.into(),
+            },
+            None => return Err(UserError::NoTargetSpecified.into()),
+        };
+
+        if let Some(arg) = positional.next() {
+            return Err(UserError::UnexpectedArgument(arg).into());
+        }
+
+        Ok(Self {
+            command,
+            dry_run,
+            restart,
+            timeout,
+            output,
+            jobs,
+            verbose,
+            message_format,
+            profile,
+            schedule,
+            trace_deps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = parse(&["-j4", "--output=prefixed", "--timeout", "1m", "a"])
+            .unwrap();
+
+        assert_eq!(args.command, Command::Build { target: "a".into() });
+        assert_eq!(args.jobs, 4);
+        assert_eq!(args.output, OutputMode::Prefixed);
+        assert_eq!(args.timeout, Some(Duration::from_secs(60)));
+
+        let args = parse(&["--message-format=json", "a"]).unwrap();
+        assert_eq!(args.message_format, MessageFormat::Json);
+
+        let args = parse(&["clean", "-n"]).unwrap();
+        assert_eq!(args.command, Command::Clean { target: None });
+        assert!(args.dry_run);
+
+        let args = parse(&["--trace-deps", "a"]).unwrap();
+        assert_eq!(args.trace_deps, TraceDeps::Report);
+        assert_eq!(args.command, Command::Build { target: "a".into() });
+
+        let args = parse(&["--trace-deps=record", "a"]).unwrap();
+        assert_eq!(args.trace_deps, TraceDeps::Record);
+
+        let args = parse(&["critical-path", "a"]).unwrap();
+        assert_eq!(args.command, Command::CriticalPath { target: "a".into() });
+
+        // targets named like the commands follow `--`
+        let args = parse(&["-n", "--", "clean"]).unwrap();
+        assert_eq!(
+            args.command,
+            Command::Build {
+                target: "clean".into()
+            }
+        );
+        assert!(args.dry_run);
+
+        let args = parse(&["clean", "--", "watch"]).unwrap();
+        assert_eq!(
+            args.command,
+            Command::Clean {
+                target: Some("watch".into())
+            }
+        );
+
+        let args = parse(&["--", "-v"]).unwrap();
+        assert_eq!(
+            args.command,
+            Command::Build {
+                target: "-v".into()
+            }
+        );
+        assert!(!args.verbose);
+
+        assert!(parse(&["watch"]).is_err());
+        assert!(parse(&["--", "critical-path", "a"]).is_err());
+        assert!(parse(&["a", "b"]).is_err());
+        assert!(parse(&["--jobs", "0", "a"]).is_err());
+        assert!(parse(&["--output"]).is_err());
+    }
+}
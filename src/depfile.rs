@@ -0,0 +1,130 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Error, Result, UserError};
+
+/// Reads the prerequisites of all the rules in a Makefile-style depfile,
+/// as emitted by `gcc -MD` or `rustc --emit=dep-info`.
+pub fn read(path: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound =>
+            UserError::NoSuchDepfile(path.to_path_buf()).into(),
+        _ => Error::IO(err),
+    })?;
+
+    Ok(parse(&text))
+}
+
+/// Parses the prerequisites of all the rules, in the order they appear,
+/// without duplicates. The targets of the rules are ignored.
+pub fn parse(text: &str) -> Vec<PathBuf> {
+    let mut deps = Vec::<PathBuf>::new();
+
+    // a backslash at the end of a line continues the rule
+    // on the next one
+    let text = text.replace("\\\r\n", " ").replace("\\\n", " ");
+
+    for line in text.lines() {
+        // comments (e.g. `# env-dep:` emitted by rustc)
+        // and blank lines
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let prerequisites = match find_separator(line) {
+            Some(ix) => &line[ix + 1..],
+            None => continue,
+        };
+
+        for word in split_words(prerequisites) {
+            let dep = PathBuf::from(word);
+
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+    }
+
+    deps
+}
+
+/// Finds the colon separating the targets from the prerequisites.
+/// Colons followed by anything else than whitespace (e.g. in `C:\`)
+/// don't count.
+fn find_separator(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+
+    (0..bytes.len()).find(|&ix| {
+        bytes[ix] == b':'
+            && (ix > 0 && bytes[ix - 1] != b'\\')
+            && bytes.get(ix + 1).is_none_or(u8::is_ascii_whitespace)
+    })
+}
+
+/// Splits on unescaped whitespace, unescaping `\ `, `\#`, `\:` and `$$`.
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ' | '#' | ':')) =>
+                word.extend(chars.next()),
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            }
+            c if c.is_whitespace() =>
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                },
+            c => word.push(c),
+        }
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        let paths = |paths: &[&str]| {
+            paths.iter().map(PathBuf::from).collect::<Vec<_>>()
+        };
+
+        // gcc -MD -MP
+        let gcc = "main.o: main.c include/a.h \\\n  include/my\\ b.h\n\n\
+                   include/a.h:\n\ninclude/my\\ b.h:\n";
+        assert_eq!(
+            parse(gcc),
+            paths(&["main.c", "include/a.h", "include/my b.h"])
+        );
+
+        // rustc --emit=dep-info
+        let rustc = "/p/target/app: src/main.rs src/cli.rs\n\n\
+                     /p/target/app.d: src/main.rs src/cli.rs\n\n\
+                     src/main.rs:\nsrc/cli.rs:\n\n\
+                     # env-dep:CARGO_PKG_NAME=app\n";
+        assert_eq!(parse(rustc), paths(&["src/main.rs", "src/cli.rs"]));
+
+        assert_eq!(
+            parse("C:\\out.o: C:\\src\\a$$.c"),
+            paths(&["C:\\src\\a$.c"])
+        );
+        assert_eq!(parse(""), paths(&[]));
+    }
+}
@@ -1,26 +1,87 @@
-use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
-};
+use std::{collections::HashMap, fmt, path::PathBuf, time::Duration};
 
 use crate::{
     error::{Error, Result, UserError},
+    state::{BuildState, FileState, Staleness},
     target::Target,
     util::ResultIterator,
 };
 
 use daggy::{petgraph::visit::IntoNeighborsDirected, NodeIndex as Nx};
 
-type DependencyDag = daggy::Dag<Node, ()>;
+type DependencyDag = daggy::Dag<Node, Edge>;
 type Identifier = PathBuf;
-type FileState = (); // TODO
 
 #[derive(Debug)]
 enum Node {
-    Target(Target),
+    Target(Box<Target>),
     NoRule(Identifier),
 }
 
+/// The kind of a dependency.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Edge {
+    /// The dependant is rebuilt whenever the dependency changes.
+    Regular,
+    /// Like `Regular` but read from the depfile or printed by the discover
+    /// task, so the dependency may have been removed since the last build.
+    Discovered,
+    /// The dependency only has to be built before the dependant
+    /// (or before its discover task, whose input it is).
+    OrderOnly,
+}
+
+impl Node {
+    fn identifier(&self) -> &Identifier {
+        match self {
+            Node::Target(target) => &target.identifier,
+            Node::NoRule(identifier) => identifier,
+        }
+    }
+}
+
+/// The reason why a target has to be rebuilt.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Reason {
+    /// Targets with no dependencies are assumed to depend
+    /// on other factors - time, environmental variables,
+    /// current directory etc.
+    NoDependencies,
+    OutputMissing,
+    NeverBuilt,
+    DependencyModified(Identifier),
+    DependencyRebuilt(Identifier),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::NoDependencies => write!(f, "no dependencies"),
+            Reason::OutputMissing => write!(f, "output missing"),
+            Reason::NeverBuilt => write!(f, "never built"),
+            Reason::DependencyModified(dep) =>
+                write!(f, "{} modified", dep.to_string_lossy()),
+            Reason::DependencyRebuilt(dep) =>
+                write!(f, "{} rebuilt", dep.to_string_lossy()),
+        }
+    }
+}
+
+/// What has to be done to bring the chosen target up to date.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// The chosen target.
+    pub target: Identifier,
+    /// Obsolete targets in the order they have to be built,
+    /// along with the reason why each of them has to be rebuilt.
+    pub targets: Vec<(Target, Reason)>,
+    /// Targets the chosen target depends on (including the target
+    /// itself) which are up to date and will be skipped.
+    pub up_to_date: Vec<Identifier>,
+    /// The current state of the files the targets depend on.
+    pub dep_states: HashMap<Identifier, FileState>,
+}
+
 // TODO think of a better name
 pub struct DependencyGraph {
     id_to_ix_map: HashMap<Identifier, Nx>,
@@ -28,6 +89,7 @@ pub struct DependencyGraph {
 }
 
 impl DependencyGraph {
+    #[allow(clippy::map_collect_result_unit, clippy::map_flatten)]
     pub fn construct(targets: Vec<Target>) -> Result<Self> {
         let mut graph = DependencyDag::new();
         let mut id_to_ix_map = HashMap::new();
@@ -39,12 +101,20 @@ impl DependencyGraph {
             .map(|target| {
                 util::add_target_node(&mut graph, &mut id_to_ix_map, target)
             })
-            .collect::<Result<_>>()?;
+            .collect::<Result<()>>()?;
 
         // add left dependency nodes - leaf nodes representing actual files
         targets
             .into_iter()
-            .map(|target| target.deps)
+            .map(|target| {
+                target
+                    .deps
+                    .into_iter()
+                    .chain(target.discovered_deps)
+                    .chain(target.dynamic_deps)
+                    .chain(target.order_deps)
+                    .chain(target.discover.into_iter().flat_map(|d| d.deps))
+            })
             .flatten()
             .for_each(|dep_id| {
                 util::add_leaf_node(&mut graph, &mut id_to_ix_map, dep_id);
@@ -57,7 +127,7 @@ impl DependencyGraph {
             .map(|target_ix| {
                 util::add_edges_to_deps(&mut graph, &id_to_ix_map, target_ix)
             })
-            .collect::<Result<_>>()?;
+            .collect::<Result<()>>()?;
 
         Ok(Self {
             graph,
@@ -65,124 +135,346 @@ impl DependencyGraph {
         })
     }
 
-    pub fn get_target_sequence(
+    /// Returns the obsolete targets the chosen target depends on
+    /// (including the target itself) in the order they have to be built
+    /// and the targets which are up to date.
+    pub fn get_plan(
         &self,
         target_id: Identifier,
-    ) -> Result<Vec<Target>> {
+        state: &BuildState,
+    ) -> Result<Plan> {
         let graph = &self.graph;
         let target_ix = *self
             .id_to_ix_map
             .get(&target_id)
-            .ok_or_else(|| UserError::NoSuchTarget(target_id))?;
+            .ok_or(UserError::NoSuchTarget(target_id))?;
 
         let depth_map = util::generate_depth_map(graph, target_ix);
-        let obsolete_leaf_nodes =
-            util::find_obsolete_leaf_nodes(graph.graph())?;
-        let obsolete_targets =
-            util::find_obsolete_targets(graph.graph(), &obsolete_leaf_nodes);
+        let node_ixs = depth_map.keys().copied().collect::<Vec<_>>();
+        let dep_states = util::dep_states(graph.graph(), &node_ixs, state)?;
+        let directly_obsolete_targets = util::find_directly_obsolete_targets(
+            graph.graph(),
+            node_ixs,
+            state,
+            &dep_states,
+        )?;
+        let obsolete_targets = util::find_obsolete_targets(
+            graph.graph(),
+            &directly_obsolete_targets,
+        );
+
+        let targets = util::get_target_sequence(
+            graph.graph(),
+            &depth_map,
+            &obsolete_targets,
+        )?;
+
+        let mut up_to_date = depth_map
+            .keys()
+            .filter(|ix| !obsolete_targets.contains_key(ix))
+            .filter_map(|ix| match &graph[*ix] {
+                Node::Target(target) => Some(target.identifier.clone()),
+                Node::NoRule(_) => None,
+            })
+            .collect::<Vec<_>>();
+        up_to_date.sort();
+
+        Ok(Plan {
+            target: graph[target_ix].identifier().clone(),
+            targets,
+            up_to_date,
+            dep_states,
+        })
+    }
+
+    /// Returns the chosen target and all the targets it depends on,
+    /// directly or indirectly, or all targets if none is chosen.
+    pub fn get_targets(
+        &self,
+        target_id: Option<Identifier>,
+    ) -> Result<Vec<&Target>> {
+        let graph = &self.graph;
+        let node_ixs = match target_id {
+            Some(target_id) => {
+                let target_ix = *self
+                    .id_to_ix_map
+                    .get(&target_id)
+                    .ok_or(UserError::NoSuchTarget(target_id))?;
+
+                util::generate_depth_map(graph, target_ix)
+                    .into_keys()
+                    .collect()
+            }
+            None => graph.graph().node_indices().collect::<Vec<_>>(),
+        };
+
+        let targets = node_ixs
+            .into_iter()
+            .filter_map(|ix| match &graph[ix] {
+                Node::Target(target) => Some(target.as_ref()),
+                Node::NoRule(_) => None,
+            })
+            .collect();
+
+        Ok(targets)
+    }
+
+    /// Returns the chain of targets the chosen target depends on
+    /// (ending with the target itself) which took the longest to build,
+    /// according to the durations recorded in the state. Targets
+    /// which have never been built are assumed to take no time.
+    pub fn get_critical_path(
+        &self,
+        target_id: Identifier,
+        state: &BuildState,
+    ) -> Result<Vec<(Identifier, Option<Duration>)>> {
+        let graph = &self.graph;
+        let target_ix = *self
+            .id_to_ix_map
+            .get(&target_id)
+            .ok_or(UserError::NoSuchTarget(target_id))?;
+
+        let duration = |ix: Nx| match &graph[ix] {
+            Node::Target(target) => state
+                .target(&target.identifier)
+                .and_then(|target_state| target_state.duration),
+            Node::NoRule(_) => None,
+        };
+        let depth_map = util::generate_depth_map(graph, target_ix);
+        let path = util::find_critical_path(
+            graph.graph(),
+            &depth_map,
+            target_ix,
+            |ix| duration(ix).unwrap_or_default(),
+        );
 
-        util::get_target_sequence(graph.graph(), &depth_map, &obsolete_targets)
+        let path = path
+            .into_iter()
+            .filter_map(|ix| match &graph[ix] {
+                Node::Target(target) =>
+                    Some((target.identifier.clone(), duration(ix))),
+                Node::NoRule(_) => None,
+            })
+            .collect();
+
+        Ok(path)
+    }
+
+    /// Returns the actual files (`NoRule` nodes) the chosen
+    /// target depends on, directly or indirectly.
+    pub fn get_leaf_dependencies(
+        &self,
+        target_id: Identifier,
+    ) -> Result<Vec<Identifier>> {
+        let graph = &self.graph;
+        let target_ix = *self
+            .id_to_ix_map
+            .get(&target_id)
+            .ok_or(UserError::NoSuchTarget(target_id))?;
+
+        let depth_map = util::generate_depth_map(graph, target_ix);
+        let leaves = depth_map
+            .keys()
+            .filter_map(|ix| match &graph[*ix] {
+                Node::NoRule(identifier) => Some(identifier.clone()),
+                Node::Target(_) => None,
+            })
+            .collect();
+
+        Ok(leaves)
     }
 }
 
 mod util {
-    use std::collections::{HashMap, VecDeque};
+    use std::collections::{hash_map::Entry, HashMap, VecDeque};
 
     use super::*;
 
     use daggy::petgraph;
-    use petgraph::prelude::{Direction, Graph};
+    use petgraph::{
+        prelude::{Direction, Graph},
+        visit::EdgeRef,
+    };
 
     pub(super) fn get_target_sequence(
-        graph: &Graph<Node, ()>,
+        graph: &Graph<Node, Edge>,
         depth_map: &HashMap<Nx, usize>,
-        obsolete_targets: &HashSet<Nx>,
-    ) -> Result<Vec<Target>> {
+        obsolete_targets: &HashMap<Nx, Reason>,
+    ) -> Result<Vec<(Target, Reason)>> {
         // filter out targets which are not in the
         // dependency graph of the chosen target
         // and sort the targets left by depth in **decreasing** order
-        let mut obsolete_targets = obsolete_targets
-            .iter()
+        let mut obsolete_ixs = obsolete_targets
+            .keys()
             .filter(|ix| depth_map.contains_key(ix))
             .copied()
             .collect::<Vec<_>>();
-        obsolete_targets.sort_by_key(|ix| depth_map[ix]);
-        obsolete_targets.reverse();
+        obsolete_ixs.sort_by_key(|ix| depth_map[ix]);
+        obsolete_ixs.reverse();
 
-        obsolete_targets
+        obsolete_ixs
             .into_iter()
             .map(|target_ix| match &graph[target_ix] {
-                Node::Target(target) => Ok(target.clone()),
+                Node::Target(target) => Ok((
+                    Target::clone(target),
+                    obsolete_targets[&target_ix].clone(),
+                )),
                 Node::NoRule(_) => Err(Error::internal(line!(), file!())),
             })
             .collect::<Result<Vec<_>>>()
     }
 
-    /// This function finds all nodes that have no dependencies -
-    /// both actual files (`NoRule` variant) and targets
-    /// (`Target` variant with no dependencies is assumed to depend
-    /// on other factors - time, environmental variables,
-    /// current directory etc.).
-    pub(super) fn find_obsolete_leaf_nodes(
-        graph: &Graph<Node, ()>,
-    ) -> Result<HashSet<Nx>> {
-        graph
-            .externals(Direction::Outgoing) // get nodes with no outgoing edges
-            .filter_map(|node_ix| match &graph[node_ix] {
-                // TODO filter `requires_rebuilding`
-                Node::Target(_target) => Some(Ok(node_ix)),
-                Node::NoRule(identifier) => {
-                    // TODO clean up this part
-                    let previous_file_state = ();
-                    let result = has_file_been_modified(
-                        &identifier,
-                        previous_file_state,
-                    );
-
-                    match result {
-                        Ok(has_been_modified) =>
-                            if has_been_modified {
-                                Some(Ok(node_ix))
-                            } else {
-                                None
-                            },
-                        Err(err) => Some(Err(err)),
+    /// Returns the current state of the files (`NoRule` variant)
+    /// the targets (among `node_ixs`) depend on. All the files are
+    /// checked upfront so that they can be hashed in parallel.
+    pub(super) fn dep_states(
+        graph: &Graph<Node, Edge>,
+        node_ixs: &[Nx],
+        state: &BuildState,
+    ) -> Result<HashMap<PathBuf, FileState>> {
+        let leaf_deps = node_ixs
+            .iter()
+            .filter_map(|node_ix| match &graph[*node_ix] {
+                Node::Target(target) =>
+                    Some((*node_ix, state.target(&target.identifier))),
+                Node::NoRule(_) => None,
+            })
+            .flat_map(|(node_ix, target_state)| {
+                tracked_deps(graph, node_ix).filter_map(move |(dep_ix, _)| {
+                    match &graph[dep_ix] {
+                        Node::NoRule(dep_id) if dep_id.is_file() => Some((
+                            dep_id,
+                            target_state
+                                .and_then(|state| state.deps.get(dep_id)),
+                        )),
+                        _ => None,
                     }
-                }
+                })
+            });
+
+        state.file_states(leaf_deps)
+    }
+
+    /// This function finds targets (among `node_ixs`) which
+    /// must be rebuilt regardless of the state of other targets -
+    /// targets with no dependencies, targets whose output is missing,
+    /// targets which have never been built and targets whose
+    /// dependencies (actual files - `NoRule` variant) have been
+    /// modified since the last build. Directories only have to exist,
+    /// their content isn't tracked.
+    pub(super) fn find_directly_obsolete_targets(
+        graph: &Graph<Node, Edge>,
+        node_ixs: Vec<Nx>,
+        state: &BuildState,
+        file_states: &HashMap<PathBuf, FileState>,
+    ) -> Result<HashMap<Nx, Reason>> {
+        node_ixs
+            .into_iter()
+            .filter_map(|node_ix| match &graph[node_ix] {
+                Node::Target(target) => find_obsolescence_reason(
+                    graph,
+                    node_ix,
+                    target,
+                    state,
+                    file_states,
+                )
+                .map(|mb_reason| mb_reason.map(|reason| (node_ix, reason)))
+                .transpose(),
+                Node::NoRule(_) => None,
             })
-            .collect::<Result<HashSet<_>>>()
+            .collect::<Result<HashMap<_, _>>>()
+    }
+
+    fn find_obsolescence_reason(
+        graph: &Graph<Node, Edge>,
+        target_ix: Nx,
+        target: &Target,
+        state: &BuildState,
+        file_states: &HashMap<PathBuf, FileState>,
+    ) -> Result<Option<Reason>> {
+        let mut deps = tracked_deps(graph, target_ix).peekable();
+
+        if deps.peek().is_none() {
+            return Ok(Some(Reason::NoDependencies));
+        }
+
+        if !target.identifier.exists() {
+            return Ok(Some(Reason::OutputMissing));
+        }
+
+        let target_state = match state.target(&target.identifier) {
+            Some(target_state) => target_state,
+            None => return Ok(Some(Reason::NeverBuilt)),
+        };
+
+        for (dep_ix, edge) in deps {
+            if let Node::NoRule(dep_id) = &graph[dep_ix] {
+                if edge == Edge::Discovered && !dep_id.exists() {
+                    return Ok(Some(Reason::DependencyModified(
+                        dep_id.clone(),
+                    )));
+                }
+
+                if dep_id.is_dir() {
+                    continue;
+                }
+
+                let previous_file_state = target_state.deps.get(dep_id);
+                let file_state = file_states.get(dep_id);
+
+                if has_file_been_modified(
+                    dep_id,
+                    previous_file_state,
+                    file_state,
+                    state.staleness(),
+                )? {
+                    return Ok(Some(Reason::DependencyModified(
+                        dep_id.clone(),
+                    )));
+                }
+            }
+        }
+
+        Ok(None)
     }
 
     pub(super) fn find_obsolete_targets(
-        graph: &Graph<Node, ()>,
-        obsolete_leaf_nodes: &HashSet<Nx>,
-    ) -> HashSet<Nx> {
+        graph: &Graph<Node, Edge>,
+        obsolete_leaf_nodes: &HashMap<Nx, Reason>,
+    ) -> HashMap<Nx, Reason> {
         // reverse short circuiting bfs:
         // skip the dependants of the targets
         // that have already been marked as obsolete
         let mut queue = VecDeque::<Nx>::new();
-        let mut obsolete_ixs = HashSet::<Nx>::new();
-
-        for leaf_ix in obsolete_leaf_nodes {
-            // no need to clear the queue since it gets drained
-            // in the while loop each time
+        let mut obsolete_ixs = HashMap::<Nx, Reason>::new();
 
+        // mark the leaf nodes first so that their reasons
+        // don't get overwritten while traversing the graph
+        for (leaf_ix, reason) in obsolete_leaf_nodes {
             match &graph[*leaf_ix] {
-                Node::Target(_) => queue.push_back(*leaf_ix),
-                Node::NoRule(_) => {
-                    let direct_dependants =
-                        graph.neighbors_directed(*leaf_ix, Direction::Incoming);
-                    queue.extend(direct_dependants);
+                Node::Target(_) => {
+                    obsolete_ixs.insert(*leaf_ix, reason.clone());
+                    queue.push_back(*leaf_ix);
+                }
+                Node::NoRule(dep_id) => {
+                    let direct_dependants = tracked_dependants(graph, *leaf_ix);
+
+                    for dependant_ix in direct_dependants {
+                        obsolete_ixs.entry(dependant_ix).or_insert_with(|| {
+                            Reason::DependencyModified(dep_id.clone())
+                        });
+                        queue.push_back(dependant_ix);
+                    }
                 }
             }
+        }
 
-            while let Some(target_ix) = queue.pop_front() {
-                let has_just_been_found = obsolete_ixs.insert(target_ix);
-
-                if has_just_been_found {
-                    let dependants = graph
-                        .neighbors_directed(target_ix, Direction::Incoming);
-                    queue.extend(dependants);
+        while let Some(target_ix) = queue.pop_front() {
+            let target_id = graph[target_ix].identifier();
+            for dependant_ix in tracked_dependants(graph, target_ix) {
+                if let Entry::Vacant(entry) = obsolete_ixs.entry(dependant_ix) {
+                    entry.insert(Reason::DependencyRebuilt(target_id.clone()));
+                    queue.push_back(dependant_ix);
                 }
             }
         }
@@ -190,6 +482,30 @@ mod util {
         obsolete_ixs
     }
 
+    /// Returns the targets and files the node depends on (along with
+    /// the kind of the dependency), ignoring the order-only dependencies.
+    fn tracked_deps(
+        graph: &Graph<Node, Edge>,
+        node_ix: Nx,
+    ) -> impl Iterator<Item = (Nx, Edge)> + '_ {
+        graph
+            .edges_directed(node_ix, Direction::Outgoing)
+            .filter(|edge| *edge.weight() != Edge::OrderOnly)
+            .map(|edge| (edge.target(), *edge.weight()))
+    }
+
+    /// Returns the targets which have to be rebuilt when the node changes,
+    /// i.e. the ones which don't depend on it as order-only.
+    fn tracked_dependants(
+        graph: &Graph<Node, Edge>,
+        node_ix: Nx,
+    ) -> impl Iterator<Item = Nx> + '_ {
+        graph
+            .edges_directed(node_ix, Direction::Incoming)
+            .filter(|edge| *edge.weight() != Edge::OrderOnly)
+            .map(|edge| edge.source())
+    }
+
     pub(super) fn add_leaf_node(
         graph: &mut DependencyDag,
         id_to_ix_map: &mut HashMap<Identifier, Nx>,
@@ -209,7 +525,7 @@ mod util {
         target: Target,
     ) -> Result<()> {
         let identifier = target.identifier.clone();
-        let node_ix = graph.add_node(Node::Target(target));
+        let node_ix = graph.add_node(Node::Target(Box::new(target)));
         let slot = id_to_ix_map.insert(identifier, node_ix);
 
         match slot {
@@ -219,37 +535,111 @@ mod util {
         }
     }
 
+    #[allow(clippy::map_collect_result_unit)]
     pub(super) fn add_edges_to_deps(
         graph: &mut DependencyDag,
         id_to_ix_map: &HashMap<Identifier, Nx>,
         target_ix: Nx,
     ) -> Result<()> {
         let deps = match &graph[target_ix] {
-            Node::Target(target) => target.deps.clone(),
+            Node::Target(target) => target
+                .deps
+                .iter()
+                .map(|dep_id| (dep_id.clone(), Edge::Regular))
+                .chain(
+                    target
+                        .discovered_deps
+                        .iter()
+                        .chain(&target.dynamic_deps)
+                        .map(|dep_id| (dep_id.clone(), Edge::Discovered)),
+                )
+                .chain(
+                    target
+                        .order_deps
+                        .iter()
+                        .chain(target.discover.iter().flat_map(|d| &d.deps))
+                        .map(|dep_id| (dep_id.clone(), Edge::OrderOnly)),
+                )
+                .collect::<Vec<_>>(),
             Node::NoRule(_) => return Ok(()), // no deps
         };
 
-        deps.iter()
-            .map(|dep_id| {
+        deps.into_iter()
+            .map(|(dep_id, edge)| {
                 id_to_ix_map
-                    .get(dep_id)
+                    .get(&dep_id)
+                    .map(|dep_ix| (*dep_ix, edge))
                     .ok_or_else(|| Error::internal(line!(), file!()))
             })
-            .map_item(|dep_ix| {
+            .map_item(|(dep_ix, edge)| {
                 graph
-                    .add_edge(target_ix, *dep_ix, ())
+                    .add_edge(target_ix, dep_ix, edge)
                     .map(|_| ())
                     .map_err(|_| UserError::DependencyCycle.into())
             })
-            .map(|result| result.flatten())
-            .collect::<Result<_>>()
+            .map(|result| result.and_then(|result| result))
+            .collect::<Result<()>>()
     }
 
     pub(super) fn has_file_been_modified(
-        _identifier: &Identifier,
-        _previous_state: FileState,
+        identifier: &Identifier,
+        previous_state: Option<&FileState>,
+        current_state: Option<&FileState>,
+        staleness: Staleness,
     ) -> Result<bool> {
-        Ok(true) // TODO for now it just rebuilds everything
+        if !identifier.exists() {
+            return Err(UserError::NoSuchDependency(identifier.clone()).into());
+        }
+
+        match (previous_state, current_state) {
+            (Some(previous_state), Some(current_state)) =>
+                Ok(!current_state.matches(previous_state, staleness)),
+            _ => Ok(true),
+        }
+    }
+
+    /// Finds the path from the deepest node to the target whose nodes'
+    /// total weight is the highest.
+    pub(super) fn find_critical_path<N, E>(
+        graph: &Graph<N, E>,
+        depth_map: &HashMap<Nx, usize>,
+        target_ix: Nx,
+        weight: impl Fn(Nx) -> Duration,
+    ) -> Vec<Nx> {
+        // a dependency is always deeper than its dependants so visiting
+        // the nodes from the deepest ensures that deps are visited first
+        let mut node_ixs = depth_map.keys().copied().collect::<Vec<_>>();
+        node_ixs.sort_by_key(|ix| std::cmp::Reverse(depth_map[ix]));
+
+        // total weight of the heaviest path ending at the node
+        // and the previous node on that path
+        let mut heaviest = HashMap::<Nx, (Duration, Option<Nx>)>::new();
+
+        for node_ix in node_ixs {
+            let heaviest_dep = graph
+                .neighbors_directed(node_ix, Direction::Outgoing)
+                .filter_map(|dep_ix| {
+                    heaviest.get(&dep_ix).map(|(total, _)| (*total, dep_ix))
+                })
+                .max_by_key(|(total, _)| *total);
+            let (total, previous) = match heaviest_dep {
+                Some((total, dep_ix)) => (total, Some(dep_ix)),
+                None => (Duration::default(), None),
+            };
+
+            heaviest.insert(node_ix, (total + weight(node_ix), previous));
+        }
+
+        let mut path = vec![];
+        let mut current = Some(target_ix);
+        while let Some(node_ix) = current {
+            path.push(node_ix);
+            current =
+                heaviest.get(&node_ix).and_then(|(_, previous)| *previous);
+        }
+        path.reverse();
+
+        path
     }
 
     pub(super) fn generate_depth_map<N, E>(
@@ -291,6 +681,13 @@ mod util {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{hash::HashAlgorithm, target::Task, test_util::TempDir};
+
+    use std::{
+        collections::HashSet,
+        fs::{self, File},
+        time::SystemTime,
+    };
 
     use daggy::petgraph::graph::node_index as n;
     use pretty_assertions::assert_eq;
@@ -300,13 +697,13 @@ mod test {
         // helper functions
         let task = |cmd: &str| Task {
             command: cmd.into(),
-            working_dir: None,
+            ..Default::default()
         };
         let target = |id: &str, deps: &[&str]| Target {
             identifier: id.into(),
             deps: deps.iter().map(|d| d.into()).collect(),
             tasks: vec![task(id)],
-            working_dir: None,
+            ..Default::default()
         };
         let ix = |id: &str, map: &HashMap<_, _>| {
             let p: &std::path::Path = id.as_ref();
@@ -352,7 +749,9 @@ mod test {
 
         // nodes that have been marked as obsolete
         // (in real code it is automated)
-        let obsolete_leaf_nodes = vec![ix("l1", &map)].into_iter().collect();
+        let obsolete_leaf_nodes = vec![(ix("l1", &map), Reason::NeverBuilt)]
+            .into_iter()
+            .collect();
 
         // get the sequence of tasks that must be executed
         // in specific order
@@ -366,27 +765,30 @@ mod test {
         .unwrap();
         let target_sequence = target_sequence
             .into_iter()
-            .map(|target| target.identifier)
+            .map(|(target, reason)| (target.identifier, reason))
             .collect::<Vec<_>>();
-        let expected_target_sequence: Vec<PathBuf> =
-            vec!["b2".into(), "a2".into()];
+        let expected_target_sequence: Vec<(PathBuf, Reason)> = vec![
+            ("b2".into(), Reason::DependencyModified("l1".into())),
+            ("a2".into(), Reason::DependencyRebuilt("b2".into())),
+        ];
 
         assert_eq!(target_sequence, expected_target_sequence);
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_find_obsolete_targets() {
         // helper functions
         let target = |id: &str, deps: &[&str]| Target {
             identifier: id.into(),
             deps: deps.iter().map(|d| d.into()).collect(),
             tasks: vec![],
-            working_dir: None,
+            ..Default::default()
         };
         let ixs = |ids: &[&str], map: &HashMap<_, Nx>| {
             ids.iter()
                 .map(|id| map[&Into::<PathBuf>::into(id)])
-                .collect()
+                .collect::<HashSet<_>>()
         };
 
         // the dependency graph:
@@ -414,16 +816,177 @@ mod test {
             graph,
             id_to_ix_map: map,
         } = DependencyGraph::construct(targets).unwrap();
-        let obsolete_leaf_nodes = ixs(&["l1", "l2"], &map);
+        let obsolete_leaf_nodes = ixs(&["l1", "l2"], &map)
+            .into_iter()
+            .map(|ix| (ix, Reason::NeverBuilt))
+            .collect();
 
         let found_targets =
-            util::find_obsolete_targets(&graph.graph(), &obsolete_leaf_nodes);
+            util::find_obsolete_targets(&graph.graph(), &obsolete_leaf_nodes)
+                .into_keys()
+                .collect::<HashSet<_>>();
         let expected_targets = ixs(&["a1", "a2", "b2", "b3"], &map);
 
         assert_eq!(found_targets, expected_targets);
     }
 
     #[test]
+    fn test_order_deps() {
+        let target = |id: &str, deps: &[&str], order_deps: &[&str]| Target {
+            identifier: id.into(),
+            deps: deps.iter().map(|d| d.into()).collect(),
+            order_deps: order_deps.iter().map(|d| d.into()).collect(),
+            ..Default::default()
+        };
+
+        // the dependency graph (order-only dependencies are dotted):
+        //
+        //     a
+        //    / .
+        //   /   .
+        // l1     b
+        //        |
+        //        |
+        //        l2*
+        //
+        // l2 is marked as obsolete
+        // b has to be rebuilt but a doesn't
+
+        #[rustfmt::skip]
+        let targets = vec![
+            target("a", &["l1"], &["b"]),
+            target("b", &["l2"], &[]),
+        ];
+        let DependencyGraph {
+            graph,
+            id_to_ix_map: map,
+        } = DependencyGraph::construct(targets).unwrap();
+        let ix = |id: &str| map[&PathBuf::from(id)];
+        let obsolete_leaf_nodes =
+            vec![(ix("l2"), Reason::NeverBuilt)].into_iter().collect();
+
+        let obsolete_targets =
+            util::find_obsolete_targets(graph.graph(), &obsolete_leaf_nodes);
+        assert_eq!(
+            obsolete_targets.into_keys().collect::<HashSet<_>>(),
+            vec![ix("b")].into_iter().collect()
+        );
+
+        // once a is obsolete too, b is built first
+        let depth_map = util::generate_depth_map(&graph, ix("a"));
+        let obsolete_leaf_nodes = vec![
+            (ix("l1"), Reason::NeverBuilt),
+            (ix("l2"), Reason::NeverBuilt),
+        ]
+        .into_iter()
+        .collect();
+        let obsolete_targets =
+            util::find_obsolete_targets(graph.graph(), &obsolete_leaf_nodes);
+        let target_sequence = util::get_target_sequence(
+            graph.graph(),
+            &depth_map,
+            &obsolete_targets,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(target, _)| target.identifier)
+        .collect::<Vec<_>>();
+
+        assert_eq!(target_sequence, vec![PathBuf::from("b"), "a".into()]);
+    }
+
+    #[test]
+    fn test_unmodified_deps() {
+        let dir = TempDir::new("deps");
+        let (out, src, assets) =
+            (dir.join("out"), dir.join("src"), dir.join("assets"));
+        fs::create_dir(&assets).unwrap();
+        fs::write(&src, "a").unwrap();
+        fs::write(&out, "").unwrap();
+        let graph = DependencyGraph::construct(vec![Target {
+            identifier: out.clone(),
+            deps: vec![src.clone(), assets.clone()],
+            ..Default::default()
+        }])
+        .unwrap();
+        let mut state = BuildState::load(
+            dir.join("dodo.lock"),
+            HashAlgorithm::default(),
+            Staleness::MtimeHash,
+        )
+        .unwrap();
+        state
+            .record(&out, &[src.clone(), assets], vec![], Duration::ZERO)
+            .unwrap();
+        let mtime = |state: &BuildState| {
+            state.target(&out).unwrap().deps[&src].mtime.unwrap()
+        };
+        let recorded_mtime = mtime(&state);
+
+        // the directory doesn't make the target obsolete
+        let plan = graph.get_plan(out.clone(), &state).unwrap();
+        assert!(plan.targets.is_empty());
+        assert!(!state.refresh(&out, &plan.dep_states));
+
+        // touched but not modified, the new mtime is recorded
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let plan = graph.get_plan(out.clone(), &state).unwrap();
+        assert!(plan.targets.is_empty());
+        assert!(plan.dep_states[&src].hash.is_some());
+        assert!(state.refresh(&out, &plan.dep_states));
+        assert!(mtime(&state) > recorded_mtime);
+
+        // no longer hashed
+        let plan = graph.get_plan(out.clone(), &state).unwrap();
+        assert!(plan.dep_states[&src].hash.is_none());
+        assert!(!state.refresh(&out, &plan.dep_states));
+
+        fs::write(&src, "b").unwrap();
+        let plan = graph.get_plan(out.clone(), &state).unwrap();
+        assert_eq!(plan.targets[0].1, Reason::DependencyModified(src));
+    }
+
+    #[test]
+    fn test_find_critical_path() {
+        // the dependency graph with nodes' weights:
+        //
+        //        0(1)
+        //       /    \
+        //    1(2)    2(5)
+        //    /  \    /
+        // 3(5)   4(1)
+        //
+        // 3 -> 1 -> 0 weighs 8 and 4 -> 2 -> 0 weighs 7,
+        // once 4 weighs 3 the latter becomes the heaviest
+        #[rustfmt::skip]
+        let graph: daggy::Dag<(), ()> = daggy::Dag::from_edges([
+            (0, 1), (0, 2),
+            (1, 3), (1, 4),
+            (2, 4),
+        ]).unwrap();
+        let depth_map = util::generate_depth_map(&graph, n(0));
+        let find = |weights: [u64; 5]| {
+            util::find_critical_path(graph.graph(), &depth_map, n(0), |ix| {
+                Duration::from_secs(weights[ix.index()])
+            })
+        };
+
+        assert_eq!(find([1, 2, 5, 5, 1]), vec![n(3), n(1), n(0)]);
+        assert_eq!(find([1, 2, 5, 5, 3]), vec![n(4), n(2), n(0)]);
+        assert_eq!(find([0, 0, 0, 0, 0]).last(), Some(&n(0)));
+    }
+
+    #[test]
+    #[allow(
+        clippy::needless_borrows_for_generic_args,
+        clippy::unnecessary_get_then_check
+    )]
     fn test_generate_depth_map() {
         // depth is the length of the longest path from
         // the target node to the dependency
@@ -0,0 +1,316 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+};
+
+use crate::{
+    error::{Error, Result, UserError},
+    isolation::Isolation,
+    log::TargetLog,
+    sandbox::Sandbox,
+    state::BuildState,
+    target::{Discover, Target, Task},
+    util::{RunOptions, LOG_TAIL_LINES},
+};
+
+/// Sets the dynamic dependencies of the targets which have a discover
+/// task to the ones found by their last discovery, even if it's stale,
+/// so that the graph can be queried without running the discover tasks.
+pub fn add_recorded_deps(targets: &mut [Target], state: &BuildState) {
+    for target in targets.iter_mut() {
+        let deps = match &target.discover {
+            Some(discover) =>
+                state.last_discovered_deps(&target.identifier, discover),
+            None => continue,
+        };
+
+        set_dynamic_deps(target, deps.unwrap_or_default().to_vec());
+    }
+}
+
+/// Runs the discover tasks of the chosen targets whose inputs have changed
+/// since the last discovery, sets the dependencies they print and records
+/// them in the state. Returns whether any discover task has been run.
+pub fn discover_deps(
+    targets: &mut [Target],
+    chosen: impl Fn(&Path) -> bool,
+    state: &mut BuildState,
+    options: &RunOptions,
+    cancel: &AtomicBool,
+) -> Result<bool> {
+    let mut discovered = false;
+
+    for target in targets.iter_mut() {
+        let discover = match &target.discover {
+            Some(discover) if chosen(&target.identifier) => discover,
+            _ => continue,
+        };
+
+        if state
+            .discovered_deps(&target.identifier, discover)?
+            .is_some()
+        {
+            continue;
+        }
+
+        let deps = run(target, discover, options, cancel)?;
+        state.record_discovery(&target.identifier, discover, deps.clone())?;
+        set_dynamic_deps(target, deps);
+        discovered = true;
+    }
+
+    Ok(discovered)
+}
+
+fn set_dynamic_deps(target: &mut Target, deps: Vec<PathBuf>) {
+    target.dynamic_deps = deps
+        .into_iter()
+        .filter(|dep| *dep != target.identifier && !target.deps.contains(dep))
+        .collect();
+}
+
+/// Runs the discover task the way target's tasks are run (with the same
+/// executor, in the sandbox or isolated if the target is), except that
+/// it can only read discovery's inputs and can't write to the project
+/// dir. Returns the paths it has printed, relative to the current dir.
+fn run(
+    target: &Target,
+    discover: &Discover,
+    options: &RunOptions,
+    cancel: &AtomicBool,
+) -> Result<Vec<PathBuf>> {
+    let task = Task {
+        command: discover.command.clone(),
+        ..Task::default()
+    };
+    let discovery = Target {
+        tasks: vec![task.clone()],
+        deps: discover.deps.clone(),
+        order_deps: vec![],
+        depfile: None,
+        discovered_deps: vec![],
+        dynamic_deps: vec![],
+        discover: None,
+        ..target.clone()
+    };
+    let context = discovery.task_context()?;
+
+    let label = target.identifier.to_string_lossy().to_string();
+    let log = TargetLog::create(
+        &options.log_dir,
+        &log_name(&target.identifier),
+        options.output,
+        label,
+    )?
+    .keep_stdout();
+
+    let project_dir = env::current_dir().map_err(Error::IO)?;
+    let sandbox = match target.sandbox {
+        true => Some(Sandbox::create(
+            project_dir.join(&options.sandbox_dir).join("discover"),
+            &project_dir,
+            &discovery,
+            &context,
+        )?),
+        false => None,
+    };
+    let working_dir = match &sandbox {
+        Some(sandbox) => sandbox.working_dir(&discovery),
+        None => discovery.resolve_working_dir()?,
+    };
+    let isolation = match target.isolated {
+        true => Some(Isolation {
+            project_dir,
+            writable: sandbox
+                .iter()
+                .map(|sandbox| sandbox.root().to_path_buf())
+                .collect(),
+            placeholders: vec![],
+        }),
+        false => None,
+    };
+
+    let status = task.run(
+        options.executor.as_ref(),
+        working_dir,
+        &target.env,
+        isolation.as_ref(),
+        &context,
+        target.timeout.or(options.timeout),
+        &log,
+        cancel,
+    );
+    // the accesses aren't checked, only the printed deps are used
+    options.executor.take_file_accesses(&log);
+    log.finish()?;
+
+    let result = status.and_then(|status| match status.success() {
+        true => Ok(()),
+        false => Err(Error::TaskFailed {
+            command: discover.command.clone(),
+            status,
+            attempts: 1,
+        }),
+    });
+    if let Err(err @ (Error::TaskFailed { .. } | Error::TimedOut { .. })) =
+        result
+    {
+        return Err(Error::TargetFailed {
+            target: target.identifier.clone(),
+            log: log.path().to_path_buf(),
+            log_tail: log.tail(LOG_TAIL_LINES).unwrap_or_default(),
+            cause: Box::new(err),
+        });
+    }
+    result?;
+
+    let stdout = log.stdout()?.join("\n");
+    let deps = parse_output(&stdout)
+        .ok_or_else(|| {
+            UserError::InvalidDiscoveryOutput(target.identifier.clone())
+        })?
+        .into_iter()
+        .map(|dep| match target.working_dir() {
+            Some(dir) => dir.join(dep),
+            None => dep,
+        })
+        .collect();
+
+    Ok(deps)
+}
+
+/// Returns the name of the log of target's discovery.
+pub fn log_name(identifier: &Path) -> PathBuf {
+    let mut name = identifier.to_path_buf().into_os_string();
+    name.push(".discover");
+
+    PathBuf::from(name)
+}
+
+/// Parses either a JSON array of paths or one path per line.
+fn parse_output(output: &str) -> Option<Vec<PathBuf>> {
+    if output.trim_start().starts_with('[') {
+        return serde_json::from_str(output).ok();
+    }
+
+    let deps = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect();
+
+    Some(deps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, sync::Arc};
+
+    use crate::{executor::MockExecutor, log::OutputMode, test_util::TempDir};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_discover_deps() {
+        let dir = TempDir::new("discover");
+        let executor = Arc::new(MockExecutor::with_output(|task| {
+            match task.argv[0].as_str() {
+                "list" => (0, "a.h\n\nb.h\n".to_string()),
+                _ => (1, String::new()),
+            }
+        }));
+        let options = RunOptions {
+            output: OutputMode::Quiet,
+            executor: executor.clone(),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+        let target = |identifier: &str, command: &str| Target {
+            identifier: identifier.into(),
+            working_dir: Some("src".into()),
+            deps: vec!["src/a.h".into()],
+            discover: Some(Discover {
+                command: command.into(),
+                deps: vec![],
+            }),
+            ..Default::default()
+        };
+        let mut targets =
+            vec![target("app", "list {target}"), target("lib", "list")];
+        let mut state = BuildState::load(
+            dir.join("dodo.lock"),
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        let cancel = AtomicBool::new(false);
+
+        let discovered = discover_deps(
+            &mut targets,
+            |target| target == Path::new("app"),
+            &mut state,
+            &options,
+            &cancel,
+        );
+        let rediscovered = discover_deps(
+            &mut targets,
+            |_| true,
+            &mut state,
+            &options,
+            &cancel,
+        );
+        let log = fs::read_to_string(dir.join("logs/app.discover.log"));
+        let mut failing = vec![target("app", "fail")];
+        let failed = discover_deps(
+            &mut failing,
+            |_| true,
+            &mut state,
+            &options,
+            &cancel,
+        );
+
+        assert_eq!(discovered.unwrap(), true);
+        // only lib is discovered, the recorded deps of app are up to date
+        assert_eq!(rediscovered.unwrap(), true);
+        assert!(matches!(failed, Err(Error::TargetFailed { .. })));
+        assert_eq!(targets[0].dynamic_deps, vec![PathBuf::from("src/b.h")]);
+        assert_eq!(targets[1].dynamic_deps, vec![PathBuf::from("src/b.h")]);
+        let tasks = executor
+            .tasks()
+            .into_iter()
+            .map(|task| (task.argv, task.cwd))
+            .collect::<Vec<_>>();
+        let cwd = env::current_dir().unwrap().join("src");
+        assert_eq!(
+            tasks,
+            vec![
+                (vec!["list".to_string(), "app".to_string()], cwd.clone()),
+                (vec!["list".to_string()], cwd.clone()),
+                (vec!["fail".to_string()], cwd),
+            ]
+        );
+        assert!(log.unwrap().ends_with("]  b.h\n"));
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let paths = |paths: &[&str]| {
+            Some(paths.iter().map(PathBuf::from).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            parse_output("a.txt\n\n  b/c.txt \n"),
+            paths(&["a.txt", "b/c.txt"])
+        );
+        assert_eq!(
+            parse_output(r#"["a.txt", "with space.txt"]"#),
+            paths(&["a.txt", "with space.txt"])
+        );
+        assert_eq!(parse_output(""), paths(&[]));
+        assert_eq!(parse_output("[\"a.txt\","), None);
+    }
+}
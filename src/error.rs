@@ -1,4 +1,11 @@
-use std::{io, path::PathBuf};
+use std::{
+    any::Any,
+    fmt,
+    io,
+    path::PathBuf,
+    process::ExitStatus,
+    time::Duration,
+};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -10,7 +17,33 @@ pub enum Error {
     TOML(toml::de::Error),
     IO(io::Error),
     Formatting(FmtError),
-    Internal { line: u32, file: &'static str },
+    TaskFailed {
+        command: String,
+        status: ExitStatus,
+        attempts: u32,
+    },
+    TimedOut {
+        command: String,
+        timeout: Duration,
+    },
+    TargetFailed {
+        target: PathBuf,
+        log: PathBuf,
+        log_tail: Vec<String>,
+        cause: Box<Error>,
+    },
+    Cancelled,
+    /// Unexpected HTTP status (0 if the response was malformed).
+    Http {
+        url: String,
+        status: u16,
+    },
+    Internal {
+        line: u32,
+        file: &'static str,
+    },
+    /// A thread building a target has panicked.
+    Panicked(String),
     Other,
 }
 
@@ -18,6 +51,69 @@ impl Error {
     pub fn internal(line: u32, file: &'static str) -> Self {
         Error::Internal { line, file }
     }
+
+    /// Converts the payload of a panic.
+    pub fn panicked(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "unknown cause".to_string(),
+            },
+        };
+
+        Error::Panicked(message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UserError(err) => write!(f, "{}", err),
+            Error::TOML(err) => write!(f, "invalid config: {}", err),
+            Error::IO(err) => write!(f, "{}", err),
+            Error::Formatting(err) => write!(f, "{}", err),
+            Error::TaskFailed {
+                command,
+                status,
+                attempts,
+            } => {
+                write!(f, "`{}` failed ({})", command, status)?;
+
+                if *attempts > 1 {
+                    write!(f, " after {} attempts", attempts)?;
+                }
+
+                Ok(())
+            }
+            Error::TimedOut { command, timeout } =>
+                write!(f, "`{}` timed out after {:?}", command, timeout),
+            Error::TargetFailed {
+                target,
+                log,
+                log_tail,
+                cause,
+            } => {
+                write!(f, "target {} failed: {}", target.display(), cause)?;
+                write!(f, "\nlast lines of {}:", log.display())?;
+
+                for line in log_tail {
+                    write!(f, "\n{}", line)?;
+                }
+
+                Ok(())
+            }
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Http { url, status: 0 } =>
+                write!(f, "invalid response from {}", url),
+            Error::Http { url, status } =>
+                write!(f, "{} responded with {}", url, status),
+            Error::Internal { line, file } =>
+                write!(f, "internal error ({}:{})", file, line),
+            Error::Panicked(message) => write!(f, "panicked: {}", message),
+            Error::Other => write!(f, "unknown error"),
+        }
+    }
 }
 
 // --- USER ERROR ---
@@ -30,6 +126,62 @@ pub enum UserError {
     DuplicateTarget,
     ConfigNotFound,
     NoSuchTarget(PathBuf),
+    NoSuchDependency(PathBuf),
+    NoSuchDepfile(PathBuf),
+    InvalidDiscoveryOutput(PathBuf),
+    NoTargetSpecified,
+    UnknownOption(String),
+    UnexpectedArgument(String),
+    Unsupported(&'static str),
+    MissingValue(String),
+    InvalidValue { option: String, value: String },
+    NoCacheDir,
+    InvalidUrl(String),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use UserError::*;
+
+        match self {
+            EmptyCommand => write!(f, "empty command"),
+            EmptyTargetIdentifier => write!(f, "empty target identifier"),
+            DependencyCycle => write!(f, "dependency cycle"),
+            DuplicateTarget => write!(f, "duplicate target"),
+            ConfigNotFound => write!(f, "dodo.toml not found"),
+            NoSuchTarget(target) =>
+                write!(f, "no such target: {}", target.display()),
+            NoSuchDependency(dep) => write!(
+                f,
+                "{} doesn't exist and there's no target producing it",
+                dep.display()
+            ),
+            NoSuchDepfile(depfile) => write!(
+                f,
+                "depfile {} hasn't been written by target's tasks",
+                depfile.display()
+            ),
+            InvalidDiscoveryOutput(target) => write!(
+                f,
+                "discover task of {} printed an invalid JSON array",
+                target.display()
+            ),
+            NoTargetSpecified => write!(f, "no target specified"),
+            UnknownOption(option) => write!(f, "unknown option {}", option),
+            UnexpectedArgument(arg) => write!(f, "unexpected argument {}", arg),
+            Unsupported(feature) =>
+                write!(f, "{} is not supported on this platform", feature),
+            MissingValue(option) => write!(f, "missing value for {}", option),
+            InvalidValue { option, value } =>
+                write!(f, "invalid value for {}: {}", option, value),
+            NoCacheDir => write!(
+                f,
+                "can't locate the cache dir, set `dir` in the [cache] section"
+            ),
+            InvalidUrl(url) =>
+                write!(f, "invalid URL {} (only http:// is supported)", url),
+        }
+    }
 }
 
 impl From<UserError> for Error {
@@ -46,6 +198,15 @@ pub enum FmtError {
     InvalidVar(String),
 }
 
+impl fmt::Display for FmtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FmtError::EmptyBrackets => write!(f, "empty brackets in command"),
+            FmtError::InvalidVar(var) => write!(f, "invalid variable {}", var),
+        }
+    }
+}
+
 impl From<dynfmt::Error<'_>> for Error {
     fn from(err: dynfmt::Error) -> Self {
         use dynfmt::{Error::*, Position::*};
@@ -0,0 +1,258 @@
+use std::{iter::once, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    deps::Plan,
+    error::Result,
+    progress::Outcome,
+    report::{JsonReporter, Reporter, TerminalReporter},
+};
+
+use serde::Serialize;
+
+/// How the progress of a build is reported.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MessageFormat {
+    /// Colored messages, status line and summary.
+    Human,
+    /// Newline-delimited JSON events on stdout (tasks' output
+    /// is not echoed, it can be found in the logs).
+    Json,
+}
+
+impl MessageFormat {
+    pub fn reporter(self) -> Arc<dyn Reporter> {
+        match self {
+            MessageFormat::Human => Arc::new(TerminalReporter::default()),
+            MessageFormat::Json => Arc::new(JsonReporter::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedTarget {
+    pub target: PathBuf,
+    pub reason: String,
+    /// Target's tasks' commands, formatted.
+    pub commands: Vec<String>,
+}
+
+/// Events of a build, passed to the reporter (and emitted one per line
+/// in the JSON message format).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Plan {
+        /// Obsolete targets in the order they will be built.
+        targets: Vec<PlannedTarget>,
+        up_to_date: Vec<PathBuf>,
+    },
+    TargetSkipped {
+        target: PathBuf,
+        reason: String,
+    },
+    TargetStarted {
+        target: PathBuf,
+    },
+    TaskStarted {
+        target: PathBuf,
+        argv: Vec<String>,
+        cwd: PathBuf,
+        attempt: u32,
+    },
+    /// Reported after `TaskFinished` if the task failed
+    /// and it's going to be retried.
+    TaskRetrying {
+        target: PathBuf,
+        argv: Vec<String>,
+        attempt: u32,
+        attempts: u32,
+        failure: String,
+        delay_ms: u64,
+    },
+    TaskFinished {
+        target: PathBuf,
+        argv: Vec<String>,
+        attempt: u32,
+        /// `None` if the task was killed by a signal, timed out
+        /// or couldn't be started.
+        exit_code: Option<i32>,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Files inside the project dir which target's tasks have accessed
+    /// without the target declaring them, reported when the tasks
+    /// are traced.
+    UndeclaredAccesses {
+        target: PathBuf,
+        inputs: Vec<PathBuf>,
+        outputs: Vec<PathBuf>,
+    },
+    TargetFinished {
+        target: PathBuf,
+        outcome: Outcome,
+        duration_ms: u64,
+        log: PathBuf,
+    },
+    BuildFinished {
+        success: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Reported in the watch mode when a change has been detected
+    /// and the running build is going to be cancelled.
+    Restarting,
+    /// Reported in the watch mode once a build has finished,
+    /// or has failed to start (e.g. due to an invalid config).
+    WatchedBuildFinished {
+        cancelled: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Reported in the watch mode while waiting for the inputs to change.
+    WaitingForChanges,
+}
+
+impl Event {
+    pub fn plan(plan: &Plan) -> Result<Self> {
+        let targets = plan
+            .targets
+            .iter()
+            .map(|(target, reason)| {
+                let context = target.task_context()?;
+                let commands = target
+                    .tasks
+                    .iter()
+                    .map(|task| {
+                        let (command, args) = task.format_command(&context)?;
+
+                        Ok(once(command)
+                            .chain(args)
+                            .collect::<Vec<_>>()
+                            .join(" "))
+                    })
+                    .collect::<Result<_>>()?;
+
+                Ok(PlannedTarget {
+                    target: target.identifier.clone(),
+                    reason: reason.to_string(),
+                    commands,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Event::Plan {
+            targets,
+            up_to_date: plan.up_to_date.clone(),
+        })
+    }
+}
+
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        deps::Reason,
+        target::{Target, Task},
+    };
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_plan() {
+        let plan = Plan {
+            target: "b".into(),
+            targets: vec![(
+                Target {
+                    identifier: "b".into(),
+                    deps: vec!["a".into()],
+                    tasks: vec![Task {
+                        command: "cp a {target}".into(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+                Reason::DependencyModified("a".into()),
+            )],
+            up_to_date: vec!["a".into()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(Event::plan(&plan).unwrap()).unwrap(),
+            json!({
+                "event": "plan",
+                "targets": [{
+                    "target": "b",
+                    "reason": "a modified",
+                    "commands": ["cp a b"],
+                }],
+                "up_to_date": ["a"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let events = [
+            Event::TaskFinished {
+                target: "a".into(),
+                argv: vec!["touch".into(), "a".into()],
+                attempt: 1,
+                exit_code: None,
+                duration_ms: 5,
+                error: Some("timed out".into()),
+            },
+            Event::TargetFinished {
+                target: "a".into(),
+                outcome: Outcome::Cached,
+                duration_ms: 5,
+                log: "logs/a".into(),
+            },
+            Event::BuildFinished {
+                success: true,
+                duration_ms: 10,
+                error: None,
+            },
+            Event::WaitingForChanges,
+        ];
+
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| serde_json::to_value(event).unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                json!({
+                    "event": "task_finished",
+                    "target": "a",
+                    "argv": ["touch", "a"],
+                    "attempt": 1,
+                    "exit_code": null,
+                    "duration_ms": 5,
+                    "error": "timed out",
+                }),
+                json!({
+                    "event": "target_finished",
+                    "target": "a",
+                    "outcome": "cached",
+                    "duration_ms": 5,
+                    "log": "logs/a",
+                }),
+                json!({
+                    "event": "build_finished",
+                    "success": true,
+                    "duration_ms": 10,
+                }),
+                json!({ "event": "waiting_for_changes" }),
+            ]
+        );
+    }
+}
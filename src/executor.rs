@@ -0,0 +1,312 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    isolation::Isolation,
+    log::{Stream, TargetLog},
+    trace::FileAccesses,
+};
+
+/// How often a running task is checked for completion or cancellation
+/// and a terminated one for exiting.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a task has to exit after receiving `SIGTERM`
+/// before it gets killed.
+pub(crate) const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// A task with its command formatted and its working dir
+/// and env resolved.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TaskSpec {
+    pub argv: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub cwd: PathBuf,
+    pub isolation: Option<Isolation>,
+}
+
+/// Starts tasks. The runner waits for them, enforcing timeouts
+/// and cancellation.
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Starts the task. Its output has to be written to `log`.
+    fn spawn(
+        &self,
+        task: &TaskSpec,
+        log: &TargetLog,
+    ) -> Result<Box<dyn Process>>;
+
+    /// Returns (and forgets) the files opened by the finished tasks
+    /// whose output has been written to the log, if the executor
+    /// traces them.
+    fn take_file_accesses(&self, _log: &TargetLog) -> Option<FileAccesses> {
+        None
+    }
+}
+
+/// A task started by an [`Executor`].
+pub trait Process {
+    /// Returns the exit status if the task has exited.
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>>;
+
+    /// Terminates the task (along with its children) and waits
+    /// for it to exit.
+    fn terminate(&mut self) -> Result<()>;
+
+    /// Waits until all of task's output has been written to the log.
+    /// Called once the task has exited or has been terminated.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Runs tasks as local processes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalExecutor;
+
+impl Executor for LocalExecutor {
+    fn spawn(
+        &self,
+        task: &TaskSpec,
+        log: &TargetLog,
+    ) -> Result<Box<dyn Process>> {
+        let mut child = command(task)?.spawn().map_err(Error::IO)?;
+        let captures =
+            spawn_captures(log, child.stdout.take(), child.stderr.take());
+
+        Ok(Box::new(LocalProcess { child, captures }))
+    }
+}
+
+/// Prepares the command of the task, with its output piped.
+pub(crate) fn command(task: &TaskSpec) -> Result<Command> {
+    let (program, args) = task
+        .argv
+        .split_first()
+        .ok_or_else(|| Error::internal(line!(), file!()))?;
+
+    let mut command = Command::new(program);
+    command
+        .current_dir(&task.cwd)
+        .envs(&task.env)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // run the task in its own process group so that the whole group
+    // can be killed and Ctrl-C reaches dodo only (that's also why
+    // stdin is detached - a background group reading from the terminal
+    // would be stopped)
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    #[cfg(target_os = "linux")]
+    if let Some(isolation) = &task.isolation {
+        isolation.apply(&mut command, &task.cwd)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    if task.isolation.is_some() {
+        return Err(
+            crate::error::UserError::Unsupported("isolated targets").into()
+        );
+    }
+
+    Ok(command)
+}
+
+/// Starts copying child's output to the log.
+pub(crate) fn spawn_captures(
+    log: &TargetLog,
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+) -> Vec<JoinHandle<Result<()>>> {
+    let captures = vec![
+        stdout.map(|stdout| spawn_capture(log, Stream::Stdout, stdout)),
+        stderr.map(|stderr| spawn_capture(log, Stream::Stderr, stderr)),
+    ];
+
+    captures.into_iter().flatten().collect()
+}
+
+struct LocalProcess {
+    child: Child,
+    captures: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Process for LocalProcess {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.child.try_wait().map_err(Error::IO)
+    }
+
+    /// Sends `SIGTERM` to child's process group and kills
+    /// the group if it doesn't exit within the grace period.
+    #[cfg(unix)]
+    fn terminate(&mut self) -> Result<()> {
+        let pgid = self.child.id() as libc::pid_t;
+        unsafe { libc::kill(-pgid, libc::SIGTERM) };
+
+        let started = Instant::now();
+        while started.elapsed() < TERMINATION_GRACE_PERIOD {
+            if self.child.try_wait().map_err(Error::IO)?.is_some() {
+                break;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        // kill the rest of the group even if the child itself has exited
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+        self.child.wait().map_err(Error::IO)?;
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn terminate(&mut self) -> Result<()> {
+        // the child may have exited in the meantime
+        let _ = self.child.kill();
+        self.child.wait().map_err(Error::IO)?;
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        join_captures(self.captures)
+    }
+}
+
+/// Waits until all of the output has been copied to the log.
+pub(crate) fn join_captures(
+    captures: Vec<JoinHandle<Result<()>>>,
+) -> Result<()> {
+    // the pipes get closed once the whole process group exits
+    for capture in captures {
+        capture
+            .join()
+            .map_err(|_| Error::internal(line!(), file!()))??;
+    }
+
+    Ok(())
+}
+
+fn spawn_capture(
+    log: &TargetLog,
+    stream: Stream,
+    reader: impl std::io::Read + Send + 'static,
+) -> JoinHandle<Result<()>> {
+    let log = log.clone();
+
+    thread::spawn(move || log.capture(stream, reader))
+}
+
+type Handler = dyn Fn(&TaskSpec) -> (i32, String) + Send + Sync;
+
+/// Doesn't spawn any processes, only records the tasks and lets
+/// the handler decide their exit codes (and output). Meant for tests.
+pub struct MockExecutor {
+    handler: Box<Handler>,
+    tasks: Mutex<Vec<TaskSpec>>,
+}
+
+impl MockExecutor {
+    /// `handler` is called for every task and returns its exit code.
+    pub fn new(
+        handler: impl Fn(&TaskSpec) -> i32 + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_output(move |task| (handler(task), String::new()))
+    }
+
+    /// `handler` is called for every task and returns its exit code
+    /// and the output it writes to stdout.
+    pub fn with_output(
+        handler: impl Fn(&TaskSpec) -> (i32, String) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            handler: Box::new(handler),
+            tasks: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns the tasks spawned so far, in the order they were spawned.
+    pub fn tasks(&self) -> Vec<TaskSpec> {
+        self.tasks
+            .lock()
+            .map(|tasks| tasks.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for MockExecutor {
+    /// All tasks succeed.
+    fn default() -> Self {
+        Self::new(|_| 0)
+    }
+}
+
+impl fmt::Debug for MockExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockExecutor")
+            .field("tasks", &self.tasks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Executor for MockExecutor {
+    fn spawn(
+        &self,
+        task: &TaskSpec,
+        log: &TargetLog,
+    ) -> Result<Box<dyn Process>> {
+        let (code, stdout) = (self.handler)(task);
+        for line in stdout.lines() {
+            log.write_line(Stream::Stdout, line)?;
+        }
+
+        self.tasks
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?
+            .push(task.clone());
+
+        Ok(Box::new(MockProcess {
+            status: exit_status(code),
+        }))
+    }
+}
+
+struct MockProcess {
+    status: ExitStatus,
+}
+
+impl Process for MockProcess {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(Some(self.status))
+    }
+
+    fn terminate(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+
+    // the code is stored in the second byte of the wait status
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+
+    ExitStatus::from_raw(code as u32)
+}
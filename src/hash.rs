@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::Hasher as _,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::error::{Error, Result};
+
+use serde::*;
+use sha2::Digest;
+
+/// Size of the chunks files are read in.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The digest used to detect changes of dependencies and to compute
+/// cache keys. XXH3 and XxHash64 are fast, BLAKE3 and SHA-256 are
+/// cryptographic (use them if the cache is shared with untrusted
+/// parties).
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Xxhash64,
+    #[default]
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Xxhash64 => "xxhash64",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Streaming hasher of the chosen algorithm.
+#[derive(Clone)]
+pub enum Hasher {
+    Xxhash64(twox_hash::XxHash64),
+    Xxh3(Box<twox_hash::xxh3::Hash64>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Xxhash64 =>
+                Hasher::Xxhash64(twox_hash::XxHash64::default()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::default()),
+            HashAlgorithm::Blake3 =>
+                Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Xxhash64(hasher) => hasher.write(bytes),
+            Hasher::Xxh3(hasher) => hasher.write(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Returns the hex-encoded digest.
+    pub fn digest(self) -> String {
+        match self {
+            Hasher::Xxhash64(hasher) => format!("{:016x}", hasher.finish()),
+            Hasher::Xxh3(hasher) => format!("{:016x}", hasher.finish()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Hashes the content of the file without loading it into memory.
+/// The digest is prefixed with the name of the algorithm (e.g.
+/// `xxh3:...`) so that digests of different algorithms never match.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let mut file = File::open(path).map_err(Error::IO)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => hasher.update(&buffer[..len]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(Error::IO(err)),
+        }
+    }
+
+    Ok(format!("{}:{}", algorithm.name(), hasher.digest()))
+}
+
+/// Hashes the files in parallel, one thread per available core.
+pub fn hash_files<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+    algorithm: HashAlgorithm,
+) -> Result<HashMap<PathBuf, String>> {
+    let paths = paths.into_iter().collect::<Vec<_>>();
+    let workers = thread::available_parallelism()
+        .map_or(1, |workers| workers.get())
+        .min(paths.len());
+
+    if workers <= 1 {
+        return paths
+            .into_iter()
+            .map(|path| Ok((path.clone(), hash_file(path, algorithm)?)))
+            .collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let hashes = Mutex::new(HashMap::with_capacity(paths.len()));
+
+    thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    // each worker takes the next unhashed file
+                    // so big files don't stall the others
+                    while let Some(path) =
+                        paths.get(next.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let hash = hash_file(path, algorithm)?;
+
+                        hashes
+                            .lock()
+                            .map_err(|_| Error::internal(line!(), file!()))?
+                            .insert((*path).clone(), hash);
+                    }
+
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles.into_iter().try_for_each(|handle| {
+            handle
+                .join()
+                .map_err(|_| Error::internal(line!(), file!()))?
+        })
+    })?;
+
+    hashes
+        .into_inner()
+        .map_err(|_| Error::internal(line!(), file!()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_hash_file() {
+        let dir = TempDir::new("hash");
+        // bigger than the buffer so that it's hashed in chunks
+        let content = (0..BUFFER_SIZE * 3 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let paths = (0..4)
+            .map(|i| {
+                let path = dir.join(i.to_string());
+                fs::write(&path, &content[i..]).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let sha256 = hash_file(&paths[0], HashAlgorithm::Sha256).unwrap();
+        let expected = format!("{:x}", sha2::Sha256::digest(&content));
+        assert_eq!(sha256, format!("sha256:{}", expected));
+
+        let blake3 = hash_file(&paths[0], HashAlgorithm::Blake3).unwrap();
+        let expected = blake3::hash(&content).to_hex();
+        assert_eq!(blake3, format!("blake3:{}", expected));
+
+        let hashes = hash_files(&paths, HashAlgorithm::Xxh3).unwrap();
+        assert_eq!(hashes.len(), 4);
+        for path in &paths {
+            let hash = hash_file(path, HashAlgorithm::Xxh3).unwrap();
+            assert_eq!(hashes[path], hash);
+        }
+        assert_ne!(hashes[&paths[0]], hashes[&paths[1]]);
+    }
+}
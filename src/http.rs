@@ -0,0 +1,172 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::error::{Error, Result, UserError};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An `http://` URL. HTTPS is not supported.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Url {
+    host: String,
+    port: u16,
+    /// Always starts with a slash.
+    path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = || UserError::InvalidUrl(url.to_string());
+
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(ix) => rest.split_at(ix),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) =>
+                (host, port.parse::<u16>().map_err(|_| invalid())?),
+            None => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(invalid().into());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Appends the path, separated with a slash.
+    pub fn join(&self, path: &str) -> Self {
+        let mut url = self.clone();
+        if !url.path.ends_with('/') {
+            url.path.push('/');
+        }
+        url.path.push_str(path.trim_start_matches('/'));
+
+        url
+    }
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Downloads the resource. Returns `false` if it doesn't exist (404).
+pub fn get(url: &Url, to: &mut impl Write) -> Result<bool> {
+    let (status, content_length, mut body) = send(url, "GET", None)?;
+
+    match status {
+        200 => {
+            let copied = io::copy(&mut body, to).map_err(Error::IO)?;
+
+            // the connection may have been closed prematurely
+            if content_length.is_some_and(|len| copied != len) {
+                return Err(Error::IO(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            Ok(true)
+        }
+        404 => Ok(false),
+        status => Err(http_error(url, status)),
+    }
+}
+
+/// Uploads `len` bytes read from `from`.
+pub fn put(url: &Url, from: &mut impl Read, len: u64) -> Result<()> {
+    let (status, ..) = send(url, "PUT", Some((from, len)))?;
+
+    match status {
+        200..=299 => Ok(()),
+        status => Err(http_error(url, status)),
+    }
+}
+
+/// Sends an HTTP/1.0 request (so that the response is never chunked)
+/// and returns the status, the length of the body (if known)
+/// and the body of the response.
+fn send(
+    url: &Url,
+    method: &str,
+    body: Option<(&mut dyn Read, u64)>,
+) -> Result<(u16, Option<u64>, impl Read)> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(Error::IO)?
+        .next()
+        .ok_or_else(|| UserError::InvalidUrl(url.to_string()))?;
+    let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+        .map_err(Error::IO)?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .map_err(Error::IO)?;
+    stream
+        .set_write_timeout(Some(IO_TIMEOUT))
+        .map_err(Error::IO)?;
+
+    let content_length = body.as_ref().map_or(0, |(_, len)| *len);
+    let head = format!(
+        "{} {} HTTP/1.0\r\nHost: {}:{}\r\nContent-Length: {}\r\n\r\n",
+        method, url.path, url.host, url.port, content_length
+    );
+    stream.write_all(head.as_bytes()).map_err(Error::IO)?;
+    if let Some((body, len)) = body {
+        let copied =
+            io::copy(&mut body.take(len), &mut stream).map_err(Error::IO)?;
+
+        if copied != len {
+            return Err(Error::IO(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+    stream.flush().map_err(Error::IO)?;
+
+    let mut response = BufReader::new(stream);
+    let mut line = String::new();
+    response.read_line(&mut line).map_err(Error::IO)?;
+
+    // e.g. `HTTP/1.0 404 Not Found`
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| http_error(url, 0))?;
+
+    let mut content_length = None;
+    loop {
+        line.clear();
+        response.read_line(&mut line).map_err(Error::IO)?;
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<u64>().ok();
+            }
+        }
+    }
+
+    // without the length the body ends when the connection is closed
+    let body = response.take(content_length.unwrap_or(u64::MAX));
+
+    Ok((status, content_length, body))
+}
+
+fn http_error(url: &Url, status: u16) -> Error {
+    Error::Http {
+        url: url.to_string(),
+        status,
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C (and `SIGTERM`) handler which only sets a flag,
+/// so that running tasks can be terminated and the build state saved.
+/// Another Ctrl-C exits immediately.
+#[cfg(unix)]
+pub fn install_handler() {
+    extern "C" fn handler(_signal: libc::c_int) {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_handler() {}
+
+pub fn flag() -> &'static AtomicBool {
+    &INTERRUPTED
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,383 @@
+use std::{
+    fs::{self, File},
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    target::{Target, TaskContext},
+};
+
+/// Where an isolated task can write. Isolated tasks run in new user,
+/// mount and network namespaces (so no root is required) - they have
+/// no network access and the project dir is read-only for them, except
+/// for the writable paths. Only supported on Linux.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Isolation {
+    /// Absolute path of the project dir.
+    pub project_dir: PathBuf,
+    /// Absolute paths of the dirs (or files) which stay writable.
+    pub writable: Vec<PathBuf>,
+    /// Empty files created in place of the missing outputs placed
+    /// directly in the project dir, since only existing files can be
+    /// made writable.
+    pub placeholders: Vec<PathBuf>,
+}
+
+impl Isolation {
+    /// Returns the isolation of target's tasks, which can write either
+    /// to the sandbox or only to the dirs of target's output and depfile.
+    /// The project dir itself never becomes writable - outputs placed
+    /// directly in it are the only writable files there and they have
+    /// to be written in place (not e.g. replaced by a renamed temporary
+    /// file).
+    pub fn new(
+        project_dir: &Path,
+        target: &Target,
+        context: &TaskContext,
+        sandbox: Option<&Path>,
+    ) -> Result<Self> {
+        let mut isolation = Self {
+            project_dir: project_dir.to_path_buf(),
+            writable: vec![],
+            placeholders: vec![],
+        };
+
+        if let Some(sandbox) = sandbox {
+            isolation.writable.push(sandbox.to_path_buf());
+
+            return Ok(isolation);
+        }
+
+        for output in
+            once(target.identifier.clone()).chain(target.depfile_path(context)?)
+        {
+            let path = project_dir.join(output);
+            let writable = match path.parent() {
+                Some(dir) if dir != project_dir => {
+                    fs::create_dir_all(dir).map_err(Error::IO)?;
+
+                    dir.to_path_buf()
+                }
+                _ => {
+                    if fs::symlink_metadata(&path).is_err() {
+                        File::create(&path).map_err(Error::IO)?;
+                        isolation.placeholders.push(path.clone());
+                    }
+
+                    path
+                }
+            };
+
+            if !isolation.writable.contains(&writable) {
+                isolation.writable.push(writable);
+            }
+        }
+
+        Ok(isolation)
+    }
+
+    /// Removes the placeholders which are still empty, so that
+    /// the outputs which the tasks haven't written don't seem to exist.
+    /// Called once the tasks have finished, whether they have failed
+    /// or not.
+    pub fn remove_placeholders(&self) {
+        for placeholder in &self.placeholders {
+            match fs::metadata(placeholder) {
+                Ok(metadata) if metadata.len() == 0 => {
+                    let _ = fs::remove_file(placeholder);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        ffi::{CStr, CString},
+        io,
+        mem,
+        os::unix::{ffi::OsStrExt, process::CommandExt},
+        path::Path,
+        process::Command,
+        ptr,
+    };
+
+    use super::Isolation;
+    use crate::error::{Error, Result};
+
+    /// Mount flags which can't be cleared in a user namespace,
+    /// so they have to be kept when the project dir is remounted.
+    /// The `ST_*` flags of `statvfs` have the same values.
+    const LOCKED_FLAGS: libc::c_ulong = libc::MS_NOSUID
+        | libc::MS_NODEV
+        | libc::MS_NOEXEC
+        | libc::MS_NOATIME
+        | libc::MS_NODIRATIME
+        | libc::MS_RELATIME;
+
+    impl Isolation {
+        /// Makes the command enter the namespaces before it's executed.
+        /// Everything the child needs is prepared beforehand since it
+        /// mustn't allocate after forking.
+        pub(crate) fn apply(
+            &self,
+            command: &mut Command,
+            cwd: &Path,
+        ) -> Result<()> {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            // the user keeps its ids inside the namespace
+            let uid_map = format!("{} {} 1", uid, uid);
+            let gid_map = format!("{} {} 1", gid, gid);
+            let project_dir = c_path(&self.project_dir)?;
+            let writable = self
+                .writable
+                .iter()
+                .map(|path| c_path(path))
+                .collect::<Result<Vec<_>>>()?;
+            let cwd = c_path(cwd)?;
+            let root = c_path("/".as_ref())?;
+            let setgroups = c_path("/proc/self/setgroups".as_ref())?;
+            let uid_map_path = c_path("/proc/self/uid_map".as_ref())?;
+            let gid_map_path = c_path("/proc/self/gid_map".as_ref())?;
+            let locked_flags = mount_flags(&project_dir)? & LOCKED_FLAGS;
+
+            let enter = move || {
+                check(unsafe {
+                    libc::unshare(
+                        libc::CLONE_NEWUSER
+                            | libc::CLONE_NEWNS
+                            | libc::CLONE_NEWNET,
+                    )
+                })?;
+                // `gid_map` can't be written unless `setgroups` is denied
+                write(&setgroups, b"deny")?;
+                write(&uid_map_path, uid_map.as_bytes())?;
+                write(&gid_map_path, gid_map.as_bytes())?;
+
+                // keep the mounts from propagating to the parent namespace
+                mount(None, &root, libc::MS_REC | libc::MS_PRIVATE)?;
+                mount(
+                    Some(&project_dir),
+                    &project_dir,
+                    libc::MS_BIND | libc::MS_REC,
+                )?;
+                for path in &writable {
+                    mount(Some(path), path, libc::MS_BIND | libc::MS_REC)?;
+                }
+                // only the top mount becomes read-only, not the writable
+                // paths mounted on top of it
+                mount(
+                    None,
+                    &project_dir,
+                    libc::MS_BIND
+                        | libc::MS_REMOUNT
+                        | libc::MS_RDONLY
+                        | locked_flags,
+                )?;
+
+                // the working dir has been entered before the mounts
+                check(unsafe { libc::chdir(cwd.as_ptr()) })
+            };
+            unsafe { command.pre_exec(enter) };
+
+            Ok(())
+        }
+    }
+
+    fn c_path(path: &Path) -> Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| Error::IO(err.into()))
+    }
+
+    /// Returns the flags of the mount containing the path.
+    fn mount_flags(path: &CStr) -> Result<libc::c_ulong> {
+        let mut stat = unsafe { mem::zeroed::<libc::statvfs>() };
+        check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })
+            .map_err(Error::IO)?;
+
+        Ok(stat.f_flag as libc::c_ulong)
+    }
+
+    fn mount(
+        source: Option<&CStr>,
+        target: &CStr,
+        flags: libc::c_ulong,
+    ) -> io::Result<()> {
+        check(unsafe {
+            libc::mount(
+                source.map_or(ptr::null(), CStr::as_ptr),
+                target.as_ptr(),
+                ptr::null(),
+                flags,
+                ptr::null(),
+            )
+        })
+    }
+
+    fn write(path: &CStr, content: &[u8]) -> io::Result<()> {
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY) };
+        check(fd)?;
+        let written = unsafe {
+            libc::write(fd, content.as_ptr() as *const _, content.len())
+        };
+        unsafe { libc::close(fd) };
+
+        match written {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::TempDir;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_writable() {
+        let dir = TempDir::new("isolation");
+        let target = Target {
+            identifier: "out/app".into(),
+            depfile: Some("out/deps/{target_filename}.d".into()),
+            ..Default::default()
+        };
+        let context = target.task_context().unwrap();
+
+        let isolation = Isolation::new(&dir, &target, &context, None).unwrap();
+        assert_eq!(
+            isolation.writable,
+            vec![dir.join("out"), dir.join("out/deps")]
+        );
+        assert_eq!(isolation.placeholders, Vec::<PathBuf>::new());
+        assert!(dir.join("out/deps").is_dir());
+        assert!(!dir.join("out/app").exists());
+
+        // only the outputs of a root-level target are writable,
+        // not the whole project dir
+        let target = Target {
+            identifier: "app".into(),
+            depfile: Some("{target}.d".into()),
+            ..Default::default()
+        };
+        let context = target.task_context().unwrap();
+        fs::write(dir.join("app"), "previous").unwrap();
+        let isolation = Isolation::new(&dir, &target, &context, None).unwrap();
+        isolation.remove_placeholders();
+        assert_eq!(
+            isolation.writable,
+            vec![dir.join("app"), dir.join("app.d")]
+        );
+        // the existing output isn't a placeholder
+        assert_eq!(isolation.placeholders, vec![dir.join("app.d")]);
+        assert!(dir.join("app").exists());
+        assert!(!dir.join("app.d").exists());
+
+        let sandbox = dir.join(".dodo/sandbox/0");
+        let isolation =
+            Isolation::new(&dir, &target, &context, Some(&sandbox)).unwrap();
+        assert_eq!(isolation.writable, vec![sandbox]);
+    }
+
+    /// Runs the script isolated in `dir` and returns whether it has
+    /// succeeded and its stdout, or `None` if the namespaces can't be
+    /// created (e.g. unprivileged user namespaces are disabled).
+    #[cfg(target_os = "linux")]
+    fn run(
+        isolation: &Isolation,
+        dir: &Path,
+        script: &str,
+    ) -> Option<(bool, String)> {
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", script]).current_dir(dir);
+        isolation.apply(&mut command, dir).unwrap();
+
+        match command.output() {
+            Ok(output) => Some((
+                output.status.success(),
+                String::from_utf8_lossy(&output.stdout).into_owned(),
+            )),
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::EPERM | libc::EACCES | libc::ENOSPC)
+                ) =>
+            {
+                eprintln!("skipped, isolation is unavailable: {}", err);
+                None
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_apply() {
+        let dir = TempDir::new("isolated");
+        fs::create_dir(dir.join("src")).unwrap();
+        let target = Target {
+            identifier: "out/app".into(),
+            depfile: Some("{target}.d".into()),
+            ..Default::default()
+        };
+        let context = target.task_context().unwrap();
+        let isolation = Isolation::new(&dir, &target, &context, None).unwrap();
+        let run = |script| run(&isolation, &dir, script);
+
+        // the output can be replaced by a renamed temporary file
+        let replaced =
+            match run("echo app > out/app.tmp && mv out/app.tmp out/app \
+                && echo deps > out/app.d")
+            {
+                Some(replaced) => replaced,
+                None => return,
+            };
+        assert_eq!(replaced, (true, String::new()));
+        assert_eq!(fs::read_to_string(dir.join("out/app")).unwrap(), "app\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("out/app.d")).unwrap(),
+            "deps\n"
+        );
+
+        // the rest of the project dir is read-only
+        assert_eq!(run("echo > src/a.c"), Some((false, String::new())));
+        assert_eq!(run("echo > undeclared"), Some((false, String::new())));
+        assert!(!dir.join("src/a.c").exists());
+        assert!(!dir.join("undeclared").exists());
+
+        // there are no network interfaces apart from the loopback
+        let (_, dev) = run("cat /proc/net/dev").unwrap();
+        let interfaces = dev
+            .lines()
+            .skip(2)
+            .filter_map(|line| line.split(':').next())
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        assert_eq!(interfaces, vec!["lo"]);
+        // and the host's loopback isn't reachable (while it is
+        // without the isolation)
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connect = format!("bash -c 'echo > /dev/tcp/127.0.0.1/{}'", port);
+        let connected = std::process::Command::new("sh")
+            .args(["-c", &connect])
+            .status()
+            .unwrap();
+        assert!(connected.success());
+        assert_eq!(run(&connect), Some((false, String::new())));
+    }
+}
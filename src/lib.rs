@@ -0,0 +1,82 @@
+//! dodo builds targets described in `dodo.toml`, rebuilding only
+//! the targets whose dependencies have changed since the last build.
+//!
+//! The [`Build`] runner loads a project, lets you query its dependency
+//! graph and builds targets:
+//!
+//! ```no_run
+//! use std::sync::atomic::AtomicBool;
+//!
+//! use dodo::{Build, RunOptions};
+//!
+//! fn main() -> dodo::Result<()> {
+//!     let mut build = Build::load(RunOptions::default())?;
+//!
+//!     for (target, reason) in &build.plan("target/app")?.targets {
+//!         println!("{} ({})", target.identifier.display(), reason);
+//!     }
+//!
+//!     build.run("target/app", &AtomicBool::new(false))
+//! }
+//! ```
+//!
+//! A [`Config`] can also be constructed (or deserialized) directly
+//! and passed to [`Build::new`].
+
+mod build;
+mod cache;
+mod depfile;
+mod deps;
+mod discover;
+mod error;
+mod event;
+mod executor;
+mod hash;
+mod http;
+pub mod interrupt;
+mod isolation;
+mod log;
+mod profile;
+mod progress;
+#[cfg(target_os = "linux")]
+mod ptrace;
+mod report;
+mod sandbox;
+mod state;
+mod target;
+#[cfg(test)]
+mod test_util;
+mod trace;
+mod util;
+#[cfg(target_os = "linux")]
+mod watch;
+
+pub use crate::{
+    build::Build,
+    cache::{CacheConfig, RemoteMode},
+    deps::{DependencyGraph, Plan, Reason},
+    error::{Error, FmtError, Result, UserError},
+    event::{Event, MessageFormat, PlannedTarget},
+    executor::{Executor, LocalExecutor, MockExecutor, Process, TaskSpec},
+    hash::HashAlgorithm,
+    log::{OutputMode, Stream, TargetLog},
+    progress::Outcome,
+    report::{JsonReporter, Reporter, SilentReporter, TerminalReporter},
+    state::{BuildState, Staleness},
+    target::{Config, Discover, Target, Task, CONFIG_FILE},
+    trace::{FileAccesses, TraceDeps, Undeclared},
+    util::{parse_duration, RunOptions, Schedule},
+};
+
+/// Rebuilds the target whenever any of its dependencies changes.
+#[cfg(target_os = "linux")]
+pub use crate::watch::watch;
+
+#[cfg(target_os = "linux")]
+pub use crate::ptrace::TracingExecutor;
+
+/// Printing used by the `dodo` binary, not a part of the stable API.
+#[doc(hidden)]
+pub mod print {
+    pub use crate::util::{print_critical_path, print_plan, print_targets};
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write as _},
+    iter::once,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    progress,
+};
+
+pub const LOG_DIR: &str = ".dodo/logs";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// How tasks' output is echoed (it is always written to the log).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputMode {
+    /// Lines are echoed as they arrive.
+    Raw,
+    /// Lines are echoed as they arrive, prefixed with the target.
+    Prefixed,
+    /// Target's output is echoed (to stdout) as one block
+    /// once the target finishes.
+    Grouped,
+    /// Nothing is echoed.
+    Quiet,
+}
+
+/// Output of target's tasks, captured to `.dodo/logs/<target>.log`.
+#[derive(Clone)]
+pub struct TargetLog {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+    mode: OutputMode,
+    /// Target's label, prefixing echoed lines in the prefixed mode
+    /// and preceding the output in the grouped mode.
+    label: String,
+    /// Output buffered in the grouped mode.
+    group: Arc<Mutex<Vec<String>>>,
+    /// Lines written to stdout, if they are kept instead of echoed.
+    stdout: Option<Arc<Mutex<Vec<String>>>>,
+}
+
+impl TargetLog {
+    /// Creates (or truncates) the log of the target in `log_dir`.
+    pub fn create(
+        log_dir: &Path,
+        identifier: &Path,
+        mode: OutputMode,
+        label: String,
+    ) -> Result<Self> {
+        let path = log_path(log_dir, identifier);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(Error::IO)?;
+        }
+        let file = File::create(&path).map_err(Error::IO)?;
+
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+            mode,
+            label,
+            group: Arc::new(Mutex::new(vec![])),
+            stdout: None,
+        })
+    }
+
+    /// Makes the log keep the lines written to stdout instead of echoing
+    /// them, so that they can be read by [`TargetLog::stdout`].
+    pub fn keep_stdout(self) -> Self {
+        Self {
+            stdout: Some(Arc::new(Mutex::new(vec![]))),
+            ..self
+        }
+    }
+
+    /// Returns the lines written to stdout so far, if they are kept.
+    pub fn stdout(&self) -> Result<Vec<String>> {
+        match &self.stdout {
+            Some(stdout) => stdout
+                .lock()
+                .map(|lines| lines.clone())
+                .map_err(|_| Error::internal(line!(), file!())),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a header preceding the output of a task.
+    pub fn write_header(&self, line: &str) -> Result<()> {
+        let timestamp = format_timestamp(SystemTime::now());
+
+        self.write_raw(&format!("[{}] {}", timestamp, line))
+    }
+
+    pub fn write_line(&self, stream: Stream, line: &str) -> Result<()> {
+        match (&self.stdout, self.mode, stream) {
+            (Some(stdout), _, Stream::Stdout) => stdout
+                .lock()
+                .map_err(|_| Error::internal(line!(), file!()))?
+                .push(line.to_string()),
+            (_, OutputMode::Raw, Stream::Stdout) => progress::println(line),
+            (_, OutputMode::Raw, Stream::Stderr) => progress::eprintln(line),
+            (_, OutputMode::Prefixed, Stream::Stdout) =>
+                progress::println(&format!("{} | {}", self.label, line)),
+            (_, OutputMode::Prefixed, Stream::Stderr) =>
+                progress::eprintln(&format!("{} | {}", self.label, line)),
+            (_, OutputMode::Grouped, _) => self
+                .group
+                .lock()
+                .map_err(|_| Error::internal(line!(), file!()))?
+                .push(line.to_string()),
+            (_, OutputMode::Quiet, _) => {}
+        }
+
+        let timestamp = format_timestamp(SystemTime::now());
+        let time = &timestamp[11..]; // skip the date
+        let marker = match stream {
+            Stream::Stdout => ' ',
+            Stream::Stderr => '!',
+        };
+
+        self.write_raw(&format!("[{}]{} {}", time, marker, line))
+    }
+
+    fn write_raw(&self, line: &str) -> Result<()> {
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?;
+
+        writeln!(file, "{}", line).map_err(Error::IO)
+    }
+
+    /// Echoes the output buffered in the grouped mode as one block.
+    pub fn finish(&self) -> Result<()> {
+        let group = self
+            .group
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?;
+
+        if group.is_empty() {
+            return Ok(());
+        }
+
+        let block = once(format!("{}:", self.label))
+            .chain(group.iter().cloned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        progress::println(&block);
+
+        Ok(())
+    }
+
+    /// Reads the stream line by line and writes it to the log
+    /// until the stream is closed.
+    pub fn capture(&self, stream: Stream, reader: impl Read) -> Result<()> {
+        let mut reader = BufReader::new(reader);
+        let mut buffer = vec![];
+
+        loop {
+            buffer.clear();
+            let read =
+                reader.read_until(b'\n', &mut buffer).map_err(Error::IO)?;
+
+            if read == 0 {
+                return Ok(());
+            }
+
+            let line = String::from_utf8_lossy(&buffer);
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            self.write_line(stream, line)?;
+        }
+    }
+
+    /// Returns up to `n` last lines of the log.
+    pub fn tail(&self, n: usize) -> Result<Vec<String>> {
+        let file = File::open(&self.path).map_err(Error::IO)?;
+        let lines = BufReader::new(file)
+            .lines()
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(Error::IO)?;
+        let skip = lines.len().saturating_sub(n);
+
+        Ok(lines.into_iter().skip(skip).collect())
+    }
+}
+
+/// Returns the path of the log of the target in `log_dir`.
+pub fn log_path(log_dir: &Path, identifier: &Path) -> PathBuf {
+    // keep only the normal components so that the log
+    // can't end up outside of the log dir
+    let name = identifier
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect::<PathBuf>();
+    let mut path = log_dir.join(name).into_os_string();
+    path.push(".log");
+
+    PathBuf::from(path)
+}
+
+/// Formats the time as `YYYY-MM-DDTHH:MM:SS.mmmZ` (UTC).
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // convert days since epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_timestamp() {
+        let at = |secs, millis| {
+            UNIX_EPOCH
+                + Duration::from_secs(secs)
+                + Duration::from_millis(millis)
+        };
+
+        assert_eq!(format_timestamp(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(at(951_825_600, 5)),
+            "2000-02-29T12:00:00.005Z"
+        );
+        assert_eq!(
+            format_timestamp(at(1_798_761_599, 999)),
+            "2026-12-31T23:59:59.999Z"
+        );
+    }
+}
@@ -1,25 +1,104 @@
-#![feature(result_flattening)]
-#![allow(clippy::iter_nth_zero)]
+mod cli;
 
-mod deps;
-mod error;
-mod target;
-mod util;
+use std::{path::PathBuf, process, sync::Arc};
 
-use deps::DependencyGraph;
-use error::{Error, Result};
-use target::Config;
+use cli::{Args, Command};
+use dodo::{
+    interrupt,
+    print,
+    Build,
+    MessageFormat,
+    OutputMode,
+    Result,
+    RunOptions,
+    TraceDeps,
+};
 
-fn main() -> Result<()> {
-    let target = std::env::args().nth(1).expect("specify target");
+use ansi_term::Colour::Red;
 
-    let dodo = util::read_config("dodo.toml")?;
-    let dodo = toml::from_str::<Config>(&dodo).map_err(Error::TOML)?;
-    let deps = DependencyGraph::construct(dodo.targets.clone())?;
-    let target_sequence = deps.get_target_sequence(target.into())?;
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}: {}", Red.paint("ERROR"), err);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    let output = match args.message_format {
+        // tasks' output would mix with the events
+        MessageFormat::Json => OutputMode::Quiet,
+        MessageFormat::Human => args.output,
+    };
+    let executor = match args.trace_deps {
+        TraceDeps::Off => RunOptions::default().executor,
+        #[cfg(target_os = "linux")]
+        _ => Arc::new(dodo::TracingExecutor::default()),
+        #[cfg(not(target_os = "linux"))]
+        _ => return Err(dodo::UserError::Unsupported("--trace-deps").into()),
+    };
+    let options = RunOptions {
+        timeout: args.timeout,
+        output,
+        jobs: args.jobs,
+        reporter: args.message_format.reporter(),
+        profile: args.profile,
+        schedule: args.schedule,
+        executor,
+        trace_deps: args.trace_deps,
+        // the rest is configured in dodo.toml
+        ..RunOptions::default()
+    };
+
+    interrupt::install_handler();
+
+    match args.command {
+        Command::Build { target } =>
+            build(target, args.dry_run, args.verbose, &options),
+        #[cfg(target_os = "linux")]
+        Command::Watch { target } =>
+            dodo::watch(target, args.restart, &options),
+        #[cfg(not(target_os = "linux"))]
+        Command::Watch { .. } =>
+            Err(dodo::UserError::Unsupported("watch").into()),
+        Command::CriticalPath { target } => critical_path(target),
+        Command::Clean { target } => clean(target, args.dry_run),
+    }
+}
+
+fn build(
+    target: PathBuf,
+    dry_run: bool,
+    verbose: bool,
+    options: &RunOptions,
+) -> Result<()> {
+    let mut build = Build::load(options.clone())?;
+    let plan = build.plan(target)?;
 
-    util::print_targets(&dodo.targets)?;
-    util::run_targets(target_sequence)?;
+    if dry_run {
+        return print::print_plan(&plan.targets);
+    }
+
+    if verbose {
+        let config = build.config();
+        print::print_targets(&config.targets, config.hash)?;
+    }
+    build.run_plan(plan, interrupt::flag())?;
 
     Ok(())
 }
+
+fn critical_path(target: PathBuf) -> Result<()> {
+    let build = Build::load(RunOptions::default())?;
+    let path = build.critical_path(target)?;
+
+    print::print_critical_path(&path);
+
+    Ok(())
+}
+
+fn clean(target: Option<PathBuf>, dry_run: bool) -> Result<()> {
+    let mut build = Build::load(RunOptions::default())?;
+
+    build.clean(target, dry_run)
+}
@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Collects spans of targets and tasks and writes them
+/// in the Chrome trace event format (viewable in Perfetto
+/// or `about:tracing`).
+pub struct Profiler {
+    enabled: bool,
+    started: Instant,
+    spans: Mutex<Vec<TraceEvent>>,
+}
+
+/// A complete (`"ph": "X"`) event, timestamps are in microseconds.
+#[derive(Debug, Clone, Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u64,
+    dur: u64,
+    pid: u32,
+    tid: usize,
+    args: Value,
+}
+
+impl Profiler {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            started: Instant::now(),
+            spans: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns a handle recording spans on the worker's track.
+    pub fn worker(&self, worker: usize) -> WorkerProfiler<'_> {
+        WorkerProfiler {
+            profiler: self,
+            worker,
+        }
+    }
+
+    /// Writes the trace, with one track (thread) per worker.
+    pub fn save(&self, path: &Path, workers: usize) -> Result<()> {
+        let spans = self
+            .spans
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?;
+
+        let thread_names = (0..workers).map(|worker| {
+            json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": worker,
+                "args": { "name": format!("worker {}", worker + 1) },
+            })
+        });
+        let events = thread_names
+            .chain(spans.iter().map(|span| json!(span)))
+            .collect::<Vec<_>>();
+        let trace = json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        });
+
+        let trace = serde_json::to_string(&trace)
+            .map_err(|_| Error::internal(line!(), file!()))?;
+
+        fs::write(path, trace).map_err(Error::IO)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct WorkerProfiler<'a> {
+    profiler: &'a Profiler,
+    worker: usize,
+}
+
+impl WorkerProfiler<'_> {
+    pub fn record(
+        &self,
+        name: String,
+        category: &'static str,
+        started: Instant,
+        args: Value,
+    ) -> Result<()> {
+        let profiler = self.profiler;
+
+        if !profiler.enabled {
+            return Ok(());
+        }
+
+        let ts = started.saturating_duration_since(profiler.started);
+        let span = TraceEvent {
+            name,
+            cat: category,
+            ph: "X",
+            ts: micros(ts),
+            dur: micros(started.elapsed()),
+            pid: 1,
+            tid: self.worker,
+            args,
+        };
+
+        profiler
+            .spans
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?
+            .push(span);
+
+        Ok(())
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{atomic::AtomicBool, Arc},
+    };
+
+    use crate::{
+        build::Build,
+        executor::MockExecutor,
+        log::OutputMode,
+        report::SilentReporter,
+        target::{Config, Target, Task},
+        test_util::TempDir,
+        util::RunOptions,
+    };
+
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_trace() {
+        let dir = TempDir::new("profile");
+        let target = |id: &str, deps: &[&str]| Target {
+            identifier: dir.join(id),
+            deps: deps.iter().map(|dep| dir.join(dep)).collect(),
+            tasks: vec![Task {
+                command: "touch {target}".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = Config {
+            targets: vec![target("b", &["a"]), target("a", &[])],
+            hash: Default::default(),
+            staleness: Default::default(),
+            cache: Default::default(),
+        };
+        let options = RunOptions {
+            output: OutputMode::Quiet,
+            jobs: 2,
+            reporter: Arc::new(SilentReporter),
+            profile: Some(dir.join("trace.json")),
+            executor: Arc::new(MockExecutor::default()),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        Build::new(config, dir.join("dodo.lock"), options)
+            .and_then(|mut build| {
+                build.run(dir.join("b"), &AtomicBool::new(false))
+            })
+            .unwrap();
+        let trace = fs::read_to_string(dir.join("trace.json")).unwrap();
+
+        let trace = serde_json::from_str::<Value>(&trace).unwrap();
+        assert_eq!(trace["displayTimeUnit"], json!("ms"));
+
+        let events = trace["traceEvents"].as_array().unwrap();
+        let (metadata, spans): (Vec<_>, Vec<_>) =
+            events.iter().partition(|event| event["ph"] == json!("M"));
+        assert_eq!(
+            metadata
+                .iter()
+                .map(|event| (&event["name"], &event["args"]["name"]))
+                .collect::<Vec<_>>(),
+            vec![
+                (&json!("thread_name"), &json!("worker 1")),
+                (&json!("thread_name"), &json!("worker 2")),
+            ]
+        );
+
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let touch = |name: &str| format!("touch {}", path(name));
+        assert_eq!(
+            spans
+                .iter()
+                .map(|span| (&span["cat"], &span["name"], &span["ph"]))
+                .collect::<Vec<_>>(),
+            vec![
+                (&json!("task"), &json!(touch("a")), &json!("X")),
+                (&json!("target"), &json!(path("a")), &json!("X")),
+                (&json!("task"), &json!(touch("b")), &json!("X")),
+                (&json!("target"), &json!(path("b")), &json!("X")),
+            ]
+        );
+        for span in &spans {
+            assert!(span["ts"].is_u64());
+            assert!(span["dur"].is_u64());
+            assert_eq!(span["pid"], json!(1));
+        }
+        assert_eq!(
+            spans[0]["args"],
+            json!({ "target": path("a"), "attempt": 1, "exit_code": 0 })
+        );
+        assert_eq!(spans[1]["args"], json!({ "outcome": "built" }));
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, Result};
+
+use ansi_term::Colour::*;
+use serde::Serialize;
+
+/// The status line currently displayed at the bottom of the terminal.
+static STATUS_LINE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Prints the line to stdout without garbling the status line.
+/// Fails if stdout has been closed, e.g. when it's piped to `head`.
+pub fn try_println(line: &str) -> io::Result<()> {
+    with_status_cleared(|| writeln!(io::stdout().lock(), "{}", line))
+}
+
+/// Prints the line to stdout without garbling the status line,
+/// ignoring a closed stdout.
+pub fn println(line: &str) {
+    let _ = try_println(line);
+}
+
+/// Prints the line to stderr without garbling the status line,
+/// ignoring a closed stderr.
+pub fn eprintln(line: &str) {
+    with_status_cleared(|| {
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    });
+}
+
+fn lock_status_line() -> MutexGuard<'static, Option<String>> {
+    STATUS_LINE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_status_cleared<T>(print: impl FnOnce() -> T) -> T {
+    let status_line = lock_status_line();
+
+    if status_line.is_some() {
+        eprint!("\r\x1b[2K");
+    }
+    let printed = print();
+    if let Some(line) = &*status_line {
+        eprint!("{}", line);
+    }
+
+    let _ = io::stderr().flush();
+
+    printed
+}
+
+fn set_status_line(line: Option<String>) {
+    let mut status_line = lock_status_line();
+
+    if status_line.is_some() {
+        eprint!("\r\x1b[2K");
+    }
+    if let Some(line) = &line {
+        eprint!("{}", line);
+    }
+
+    let _ = io::stderr().flush();
+    *status_line = line;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Built,
+    /// The output was restored from the cache.
+    Cached,
+    Failed,
+    Cancelled,
+}
+
+impl Outcome {
+    pub fn of(result: &Result<Outcome>) -> Self {
+        match result {
+            Ok(outcome) => *outcome,
+            Err(Error::Cancelled) => Outcome::Cancelled,
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
+
+/// Tracks the progress of a build, displays the status line
+/// (if enabled and stderr is a terminal) and prints the summary.
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    show_status: bool,
+    running: Vec<(PathBuf, Instant)>,
+    finished: Vec<(PathBuf, Outcome, Duration)>,
+}
+
+impl Progress {
+    pub fn new(total: usize, show_status: bool) -> Self {
+        Self {
+            total,
+            show_status: show_status && io::stderr().is_terminal(),
+            running: vec![],
+            finished: vec![],
+        }
+    }
+
+    pub fn start(&mut self, target: &Path) {
+        self.running.push((target.to_path_buf(), Instant::now()));
+        self.refresh();
+    }
+
+    pub fn finish(
+        &mut self,
+        target: &Path,
+        outcome: Outcome,
+        duration: Duration,
+    ) {
+        let ix = self.running.iter().position(|(id, _)| id == target);
+
+        if let Some(ix) = ix {
+            let (id, _) = self.running.remove(ix);
+            self.finished.push((id, outcome, duration));
+        }
+
+        self.refresh();
+    }
+
+    /// Redraws the status line, e.g. `[3/12] building b2 ... 4.2s`.
+    pub fn refresh(&self) {
+        if !self.show_status {
+            return;
+        }
+
+        let line = if self.running.is_empty() {
+            None
+        } else {
+            let running = self
+                .running
+                .iter()
+                .map(|(id, started)| {
+                    format!(
+                        "{} ... {:.1}s",
+                        id.to_string_lossy(),
+                        started.elapsed().as_secs_f64()
+                    )
+                })
+                .collect::<Vec<_>>();
+            let line = format!(
+                "[{}/{}] building {}",
+                self.finished.len() + 1,
+                self.total,
+                running.join(", ")
+            );
+
+            Some(truncate(&line, terminal_width()))
+        };
+
+        set_status_line(line);
+    }
+
+    /// Clears the status line and prints the duration of each executed
+    /// target along with the targets which were up to date.
+    pub fn print_summary(&self, up_to_date: &[PathBuf]) {
+        set_status_line(None);
+
+        if self.finished.is_empty() && up_to_date.is_empty() {
+            return;
+        }
+
+        println(&format!("{}:", Green.paint("SUMMARY")));
+
+        for (id, outcome, duration) in &self.finished {
+            let outcome = match outcome {
+                Outcome::Built => Green.paint("built"),
+                Outcome::Cached => Green.paint("restored from cache"),
+                Outcome::Failed => Red.paint("failed"),
+                Outcome::Cancelled => Yellow.paint("cancelled"),
+            };
+
+            println(&format!(
+                "  {} {} in {:.1}s",
+                id.to_string_lossy(),
+                outcome,
+                duration.as_secs_f64()
+            ));
+        }
+
+        for id in up_to_date {
+            let skipped = Fixed(242).paint("skipped, up to date");
+            println(&format!("  {} {}", id.to_string_lossy(), skipped));
+        }
+    }
+}
+
+fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        line.to_string()
+    } else if width < 3 {
+        // too narrow for the ellipsis
+        line.chars().take(width).collect()
+    } else {
+        let mut line = line
+            .chars()
+            .take(width.saturating_sub(3))
+            .collect::<String>();
+        line.push_str("...");
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("building a", 20), "building a");
+        assert_eq!(truncate("building a", 8), "build...");
+        assert_eq!(truncate("building a", 3), "...");
+        assert_eq!(truncate("building a", 2), "bu");
+        assert_eq!(truncate("building a", 0), "");
+    }
+}
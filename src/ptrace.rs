@@ -0,0 +1,586 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    io,
+    mem,
+    os::unix::{
+        ffi::OsStringExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Component, Path, PathBuf},
+    process::ExitStatus,
+    ptr,
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use crate::{
+    error::{Error, Result},
+    executor::{
+        self,
+        Executor,
+        Process,
+        TaskSpec,
+        POLL_INTERVAL,
+        TERMINATION_GRACE_PERIOD,
+    },
+    log::TargetLog,
+    trace::FileAccesses,
+};
+
+/// Longest path read from tracee's memory.
+const PATH_MAX: usize = 4096;
+
+/// `struct ptrace_syscall_info` from `linux/ptrace.h`, with the union
+/// flattened - `data[0]` is the syscall number at the entry
+/// and the return value at the exit, followed by the arguments.
+#[repr(C)]
+#[derive(Default)]
+struct SyscallInfo {
+    op: u8,
+    reserved: u8,
+    flags: u16,
+    arch: u32,
+    instruction_pointer: u64,
+    stack_pointer: u64,
+    data: [u64; 8],
+}
+
+const PTRACE_GET_SYSCALL_INFO: u32 = 0x420e;
+const PTRACE_SYSCALL_INFO_ENTRY: u8 = 1;
+const PTRACE_SYSCALL_INFO_EXIT: u8 = 2;
+
+/// Runs tasks as local processes traced with `ptrace`, recording
+/// the files they (and their children) open. Tasks can't use `ptrace`
+/// themselves (e.g. run a debugger) while being traced.
+#[derive(Debug, Default)]
+pub struct TracingExecutor {
+    /// Accessed files by target's log.
+    accesses: Arc<Mutex<HashMap<PathBuf, FileAccesses>>>,
+}
+
+impl Executor for TracingExecutor {
+    fn spawn(
+        &self,
+        task: &TaskSpec,
+        log: &TargetLog,
+    ) -> Result<Box<dyn Process>> {
+        let task = task.clone();
+        let (started_sender, started) = mpsc::channel();
+        let (exited_sender, exited) = mpsc::channel();
+
+        // the tracer has to be the thread which has started the task
+        let tracer = thread::spawn(move || {
+            let mut command = executor::command(&task)?;
+            unsafe {
+                command.pre_exec(|| {
+                    let traced = libc::ptrace(
+                        libc::PTRACE_TRACEME as _,
+                        0,
+                        ptr::null_mut::<libc::c_void>(),
+                        ptr::null_mut::<libc::c_void>(),
+                    );
+
+                    match traced {
+                        -1 => Err(io::Error::last_os_error()),
+                        _ => Ok(()),
+                    }
+                })
+            };
+
+            let pid = match command.spawn() {
+                Ok(mut child) => {
+                    let pid = child.id() as libc::pid_t;
+                    // the child is reaped by the tracer, not by `Child`
+                    let _ = started_sender.send(Ok((
+                        pid,
+                        child.stdout.take(),
+                        child.stderr.take(),
+                    )));
+                    pid
+                }
+                Err(err) => {
+                    let _ = started_sender.send(Err(Error::IO(err)));
+                    return Ok(FileAccesses::default());
+                }
+            };
+
+            Tracer::new(pid).run(|status| {
+                let _ = exited_sender.send(status);
+            })
+        });
+
+        let (pid, stdout, stderr) = started
+            .recv()
+            .map_err(|_| Error::internal(line!(), file!()))??;
+        let captures = executor::spawn_captures(log, stdout, stderr);
+
+        Ok(Box::new(TracedProcess {
+            pid,
+            status: None,
+            exited,
+            tracer,
+            captures,
+            log: log.path().to_path_buf(),
+            accesses: self.accesses.clone(),
+        }))
+    }
+
+    fn take_file_accesses(&self, log: &TargetLog) -> Option<FileAccesses> {
+        let mut accesses = self.accesses.lock().ok()?;
+
+        Some(accesses.remove(log.path()).unwrap_or_default())
+    }
+}
+
+struct TracedProcess {
+    pid: libc::pid_t,
+    status: Option<ExitStatus>,
+    exited: Receiver<ExitStatus>,
+    tracer: JoinHandle<Result<FileAccesses>>,
+    captures: Vec<JoinHandle<Result<()>>>,
+    log: PathBuf,
+    accesses: Arc<Mutex<HashMap<PathBuf, FileAccesses>>>,
+}
+
+impl Process for TracedProcess {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.status.is_none() {
+            self.status = match self.exited.try_recv() {
+                Ok(status) => Some(status),
+                Err(TryRecvError::Empty) => None,
+                // the tracer has failed, its error is returned by `finish`
+                Err(TryRecvError::Disconnected) =>
+                    return Err(Error::internal(line!(), file!())),
+            };
+        }
+
+        Ok(self.status)
+    }
+
+    /// Sends `SIGTERM` to task's process group and kills
+    /// the group if it doesn't exit within the grace period.
+    fn terminate(&mut self) -> Result<()> {
+        unsafe { libc::kill(-self.pid, libc::SIGTERM) };
+
+        let started = Instant::now();
+        while started.elapsed() < TERMINATION_GRACE_PERIOD {
+            if self.try_wait()?.is_some() {
+                break;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        unsafe { libc::kill(-self.pid, libc::SIGKILL) };
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let accesses = self
+            .tracer
+            .join()
+            .map_err(|_| Error::internal(line!(), file!()))??;
+
+        self.accesses
+            .lock()
+            .map_err(|_| Error::internal(line!(), file!()))?
+            .entry(self.log)
+            .or_default()
+            .extend(accesses);
+
+        executor::join_captures(self.captures)
+    }
+}
+
+/// Follows the task and all its descendants until they exit.
+struct Tracer {
+    root: libc::pid_t,
+    /// Tracees which have stopped at least once.
+    seen: HashSet<libc::pid_t>,
+    /// The entries of the syscalls the tracees are in, if they're
+    /// of interest.
+    pending: HashMap<libc::pid_t, [u64; 8]>,
+    accesses: FileAccesses,
+}
+
+impl Tracer {
+    fn new(root: libc::pid_t) -> Self {
+        Self {
+            root,
+            seen: HashSet::new(),
+            pending: HashMap::new(),
+            accesses: FileAccesses::default(),
+        }
+    }
+
+    /// Calls `exited` as soon as the task itself exits but keeps
+    /// following its descendants which are still running.
+    fn run(
+        mut self,
+        mut exited: impl FnMut(ExitStatus),
+    ) -> Result<FileAccesses> {
+        loop {
+            let mut status = 0;
+            // only the tracees of this thread, other threads may be
+            // tracing other tasks
+            let pid = unsafe {
+                libc::waitpid(-1, &mut status, libc::__WALL | libc::__WNOTHREAD)
+            };
+
+            if pid == -1 {
+                let err = io::Error::last_os_error();
+
+                match err.raw_os_error() {
+                    Some(libc::ECHILD) => return Ok(self.accesses),
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(Error::IO(err)),
+                }
+            }
+
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                self.pending.remove(&pid);
+
+                if pid == self.root {
+                    exited(ExitStatus::from_raw(status));
+                }
+
+                continue;
+            }
+
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+
+            let signal = libc::WSTOPSIG(status);
+            let first_stop = self.seen.insert(pid);
+            let injected = if signal == libc::SIGTRAP | 0x80 {
+                self.syscall_stop(pid);
+                0
+            } else if status >> 16 != 0 {
+                // fork, clone, vfork and exec events
+                0
+            } else if first_stop && pid == self.root {
+                // `SIGTRAP` after the initial exec
+                set_options(pid);
+                0
+            } else if first_stop && signal == libc::SIGSTOP {
+                // new children start stopped
+                0
+            } else {
+                signal
+            };
+
+            // the tracee may have been killed in the meantime
+            unsafe {
+                libc::ptrace(
+                    libc::PTRACE_SYSCALL as _,
+                    pid,
+                    ptr::null_mut::<libc::c_void>(),
+                    injected as usize as *mut libc::c_void,
+                )
+            };
+        }
+    }
+
+    fn syscall_stop(&mut self, pid: libc::pid_t) {
+        let mut info = SyscallInfo::default();
+        let size = unsafe {
+            libc::ptrace(
+                PTRACE_GET_SYSCALL_INFO as _,
+                pid,
+                mem::size_of::<SyscallInfo>() as *mut libc::c_void,
+                &mut info as *mut SyscallInfo as *mut libc::c_void,
+            )
+        };
+        if size <= 0 {
+            return;
+        }
+
+        match info.op {
+            PTRACE_SYSCALL_INFO_ENTRY
+                if opened_path(
+                    info.data[0] as libc::c_long,
+                    &info.data[1..],
+                )
+                .is_some() =>
+            {
+                self.pending.insert(pid, info.data);
+            }
+            PTRACE_SYSCALL_INFO_EXIT =>
+                if let Some(entry) = self.pending.remove(&pid) {
+                    // the file has been opened successfully
+                    if info.data[0] as i64 >= 0 {
+                        self.record(pid, entry);
+                    }
+                },
+            _ => {}
+        }
+    }
+
+    fn record(&mut self, pid: libc::pid_t, entry: [u64; 8]) {
+        let (dirfd, path, flags) =
+            match opened_path(entry[0] as libc::c_long, &entry[1..]) {
+                Some(OpenedPath { dirfd, path, flags }) => (dirfd, path, flags),
+                None => return,
+            };
+        let flags = match flags {
+            Flags::Known(flags) => flags,
+            // `struct open_how` starts with the flags
+            Flags::At(address) => match read_memory(pid, address, 8) {
+                Some(bytes) if bytes.len() == 8 => {
+                    let mut flags = [0; 8];
+                    flags.copy_from_slice(&bytes);
+
+                    u64::from_ne_bytes(flags) as libc::c_int
+                }
+                _ => return,
+            },
+        };
+        if flags & (libc::O_DIRECTORY | libc::O_PATH) != 0 {
+            return;
+        }
+
+        let path = match read_path(pid, path) {
+            Some(path) => path,
+            None => return,
+        };
+        let path = if path.is_absolute() {
+            path
+        } else {
+            let base = match dirfd {
+                libc::AT_FDCWD => format!("/proc/{}/cwd", pid),
+                fd => format!("/proc/{}/fd/{}", pid, fd),
+            };
+
+            match fs::read_link(base) {
+                Ok(base) => base.join(path),
+                Err(_) => return,
+            }
+        };
+        let path = normalize(&path);
+
+        let written = flags & libc::O_ACCMODE != libc::O_RDONLY
+            || flags & (libc::O_CREAT | libc::O_TRUNC) != 0;
+        if written {
+            self.accesses.written.insert(path);
+        } else {
+            self.accesses.read.insert(path);
+        }
+    }
+}
+
+fn set_options(pid: libc::pid_t) {
+    let options = libc::PTRACE_O_TRACESYSGOOD
+        | libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC
+        | libc::PTRACE_O_EXITKILL;
+
+    unsafe {
+        libc::ptrace(
+            libc::PTRACE_SETOPTIONS as _,
+            pid,
+            ptr::null_mut::<libc::c_void>(),
+            options as usize as *mut libc::c_void,
+        )
+    };
+}
+
+struct OpenedPath {
+    dirfd: libc::c_int,
+    /// Address of the path in tracee's memory.
+    path: u64,
+    flags: Flags,
+}
+
+enum Flags {
+    Known(libc::c_int),
+    /// Address of `struct open_how` in tracee's memory.
+    At(u64),
+}
+
+/// Returns the path opened (or renamed to) by the syscall, if it's
+/// one of the syscalls opening files.
+fn opened_path(nr: libc::c_long, args: &[u64]) -> Option<OpenedPath> {
+    let written = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+    let (dirfd, path, flags) = match nr {
+        libc::SYS_openat =>
+            (args[0] as libc::c_int, args[1], Flags::Known(args[2] as _)),
+        libc::SYS_openat2 =>
+            (args[0] as libc::c_int, args[1], Flags::At(args[2])),
+        libc::SYS_renameat2 =>
+            (args[2] as libc::c_int, args[3], Flags::Known(written)),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_renameat =>
+            (args[2] as libc::c_int, args[3], Flags::Known(written)),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open => (libc::AT_FDCWD, args[0], Flags::Known(args[1] as _)),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_creat => (libc::AT_FDCWD, args[0], Flags::Known(written)),
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_rename => (libc::AT_FDCWD, args[1], Flags::Known(written)),
+        _ => return None,
+    };
+
+    Some(OpenedPath { dirfd, path, flags })
+}
+
+/// Reads a NUL-terminated path from tracee's memory.
+fn read_path(pid: libc::pid_t, address: u64) -> Option<PathBuf> {
+    let mut path = vec![];
+
+    while path.len() < PATH_MAX {
+        // don't read across pages, the next one may not be mapped
+        let address = address + path.len() as u64;
+        let page_end = (address / 4096 + 1) * 4096;
+        let chunk = read_memory(pid, address, (page_end - address) as usize)?;
+
+        match chunk.iter().position(|byte| *byte == 0) {
+            Some(end) => {
+                path.extend_from_slice(&chunk[..end]);
+
+                return Some(OsString::from_vec(path).into());
+            }
+            None => path.extend_from_slice(&chunk),
+        }
+    }
+
+    None
+}
+
+fn read_memory(pid: libc::pid_t, address: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    let local = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: len,
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: len,
+    };
+
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    if read <= 0 {
+        return None;
+    }
+    buffer.truncate(read as usize);
+
+    Some(buffer)
+}
+
+/// Removes `.` and `..` from the absolute path without touching
+/// the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{collections::BTreeMap, time::Duration};
+
+    use crate::{log::OutputMode, test_util::TempDir};
+
+    /// Spawns `sh -c script` in `dir`, or returns `None` if tracing
+    /// is denied (e.g. by container's seccomp profile).
+    fn spawn(
+        executor: &TracingExecutor,
+        dir: &Path,
+        script: &str,
+        log: &TargetLog,
+    ) -> Option<Box<dyn Process>> {
+        let task = TaskSpec {
+            argv: vec!["sh".into(), "-c".into(), script.into()],
+            env: BTreeMap::new(),
+            cwd: dir.to_path_buf(),
+            isolation: None,
+        };
+
+        match executor.spawn(&task, log) {
+            Ok(process) => Some(process),
+            Err(Error::IO(err))
+                if err.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                eprintln!("skipped, ptrace is denied: {}", err);
+                None
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn test_tracing_executor() {
+        let dir = TempDir::new("ptrace");
+        let dir = dir.canonicalize().unwrap();
+        fs::write(dir.join("undeclared"), "input").unwrap();
+        let log = TargetLog::create(
+            &dir.join("logs"),
+            Path::new("out"),
+            OutputMode::Quiet,
+            "out".into(),
+        )
+        .unwrap();
+        let executor = TracingExecutor::default();
+
+        // the shell forks `cat` which reads the file
+        let mut process =
+            match spawn(&executor, &dir, "cat undeclared > out; true", &log) {
+                Some(process) => process,
+                None => return,
+            };
+        let status = loop {
+            if let Some(status) = process.try_wait().unwrap() {
+                break status;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        process.finish().unwrap();
+        let accesses = executor.take_file_accesses(&log).unwrap();
+
+        assert!(status.success());
+        assert!(accesses.read.contains(&dir.join("undeclared")));
+        assert!(accesses.written.contains(&dir.join("out")));
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "input");
+        // the accesses are forgotten once taken
+        assert_eq!(
+            executor.take_file_accesses(&log),
+            Some(FileAccesses::default())
+        );
+
+        // a terminated task is followed until it exits
+        let mut process = spawn(&executor, &dir, "sleep 10; true", &log)
+            .expect("ptrace has been allowed");
+        let started = Instant::now();
+        process.terminate().unwrap();
+        let status = process.try_wait().unwrap();
+        process.finish().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            status.and_then(|status| status.signal()),
+            Some(libc::SIGTERM)
+        );
+    }
+}
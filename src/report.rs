@@ -0,0 +1,400 @@
+use std::{
+    fmt,
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    event::Event,
+    progress::{self, Progress},
+};
+
+use ansi_term::Colour::*;
+
+/// Receives the events of builds and presents them. Events of tasks
+/// are reported from the threads running them.
+pub trait Reporter: fmt::Debug + Send + Sync {
+    fn report(&self, event: &Event) -> Result<()>;
+
+    /// Called periodically while targets are running, e.g. to redraw
+    /// elapsed times.
+    fn refresh(&self) {}
+}
+
+/// Prints the commands, a status line and a summary of the build
+/// for humans.
+#[derive(Debug, Default)]
+pub struct TerminalReporter {
+    build: Mutex<TerminalBuild>,
+}
+
+#[derive(Debug, Default)]
+struct TerminalBuild {
+    progress: Option<Progress>,
+    up_to_date: Vec<PathBuf>,
+}
+
+impl TerminalReporter {
+    fn lock(&self) -> MutexGuard<'_, TerminalBuild> {
+        self.build.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Reporter for TerminalReporter {
+    fn report(&self, event: &Event) -> Result<()> {
+        let mut build = self.lock();
+
+        match event {
+            Event::Plan { targets, .. } => {
+                for target in targets {
+                    for command in &target.commands {
+                        progress::println(command);
+                    }
+                }
+
+                build.progress = Some(Progress::new(targets.len(), true));
+                build.up_to_date.clear();
+            }
+            Event::TargetSkipped { target, .. } =>
+                build.up_to_date.push(target.clone()),
+            Event::TargetStarted { target } =>
+                if let Some(progress) = &mut build.progress {
+                    progress.start(target);
+                },
+            Event::TaskStarted { argv, .. } =>
+                progress::println(&format!("executing: {}", argv.join(" "))),
+            Event::TaskRetrying {
+                argv,
+                attempt,
+                attempts,
+                failure,
+                delay_ms,
+                ..
+            } => {
+                let message = format!(
+                    "attempt {}/{} of `{}` failed ({}), retrying in {:?}",
+                    attempt,
+                    attempts,
+                    argv.join(" "),
+                    failure,
+                    Duration::from_millis(*delay_ms)
+                );
+                progress::println(&format!(
+                    "{}: {}",
+                    Yellow.paint("RETRY"),
+                    message
+                ));
+            }
+            Event::TaskFinished {
+                argv,
+                attempt,
+                exit_code: Some(0),
+                ..
+            } if *attempt > 1 => {
+                let message = format!(
+                    "`{}` succeeded after {} attempts",
+                    argv.join(" "),
+                    attempt
+                );
+                progress::println(&format!(
+                    "{}: {}",
+                    Green.paint("RETRY"),
+                    message
+                ));
+            }
+            Event::TaskFinished { .. } => {}
+            Event::UndeclaredAccesses {
+                target,
+                inputs,
+                outputs,
+            } => {
+                let list = |paths: &[PathBuf]| {
+                    paths
+                        .iter()
+                        .map(|path| path.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+
+                if !inputs.is_empty() {
+                    progress::println(&format!(
+                        "{}: {} reads undeclared {}",
+                        Yellow.paint("WARNING"),
+                        target.to_string_lossy(),
+                        list(inputs)
+                    ));
+                }
+
+                if !outputs.is_empty() {
+                    progress::println(&format!(
+                        "{}: {} writes undeclared {}",
+                        Yellow.paint("WARNING"),
+                        target.to_string_lossy(),
+                        list(outputs)
+                    ));
+                }
+            }
+            Event::TargetFinished {
+                target,
+                outcome,
+                duration_ms,
+                ..
+            } =>
+                if let Some(progress) = &mut build.progress {
+                    let duration = Duration::from_millis(*duration_ms);
+                    progress.finish(target, *outcome, duration);
+                },
+            Event::BuildFinished { .. } =>
+                if let Some(progress) = build.progress.take() {
+                    progress.print_summary(&build.up_to_date);
+                },
+            Event::Restarting =>
+                progress::println(&Yellow.paint("RESTARTING").to_string()),
+            Event::WatchedBuildFinished {
+                cancelled: true, ..
+            } => progress::println(&Yellow.paint("CANCELLED").to_string()),
+            Event::WatchedBuildFinished {
+                error: Some(error), ..
+            } => {
+                let message = format!("{}: {}", Red.paint("ERROR"), error);
+                progress::println(&message);
+            }
+            Event::WatchedBuildFinished { .. } =>
+                progress::println(&Green.paint("DONE").to_string()),
+            Event::WaitingForChanges => progress::println(
+                &Fixed(242).paint("waiting for changes...").to_string(),
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn refresh(&self) {
+        if let Some(progress) = &self.lock().progress {
+            progress.refresh();
+        }
+    }
+}
+
+/// Writes the events to stdout (or another writer) as newline-delimited
+/// JSON. Reporting fails once the output gets closed, so that the build
+/// stops.
+#[derive(Default)]
+pub struct JsonReporter {
+    /// Stdout if not set.
+    writer: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonReporter {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Some(Mutex::new(Box::new(writer))),
+        }
+    }
+}
+
+impl fmt::Debug for JsonReporter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonReporter").finish_non_exhaustive()
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn report(&self, event: &Event) -> Result<()> {
+        let line = serde_json::to_string(event)
+            .map_err(|_| Error::internal(line!(), file!()))?;
+
+        match &self.writer {
+            Some(writer) => {
+                let mut writer =
+                    writer.lock().unwrap_or_else(PoisonError::into_inner);
+                writeln!(writer, "{}", line).map_err(Error::IO)
+            }
+            None => progress::try_println(&line).map_err(Error::IO),
+        }
+    }
+}
+
+/// Ignores all events.
+#[derive(Debug, Default)]
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn report(&self, _event: &Event) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        io,
+        sync::{atomic::AtomicBool, Arc},
+    };
+
+    use crate::{
+        build::Build,
+        executor::MockExecutor,
+        log::OutputMode,
+        target::{Config, Target, Task},
+        test_util::TempDir,
+        util::RunOptions,
+    };
+
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+
+    /// A writer whose content can be read after it has been
+    /// passed to a reporter.
+    #[derive(Debug, Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Builds `b` which depends on `a`, both being built by one task.
+    fn build(name: &str, reporter: Arc<dyn Reporter>) -> (TempDir, Result<()>) {
+        let dir = TempDir::new(name);
+        let target = |id: &str, deps: &[&str]| Target {
+            identifier: dir.join(id),
+            deps: deps.iter().map(|dep| dir.join(dep)).collect(),
+            tasks: vec![Task {
+                command: "touch {target}".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = Config {
+            targets: vec![target("b", &["a"]), target("a", &[])],
+            hash: Default::default(),
+            staleness: Default::default(),
+            cache: Default::default(),
+        };
+        let options = RunOptions {
+            output: OutputMode::Quiet,
+            reporter,
+            executor: Arc::new(MockExecutor::default()),
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        let result = Build::new(config, dir.join("dodo.lock"), options)
+            .and_then(|mut build| {
+                build.run(dir.join("b"), &AtomicBool::new(false))
+            });
+
+        (dir, result)
+    }
+
+    #[test]
+    fn test_json_reporter() {
+        let buffer = Buffer::default();
+        let (dir, result) =
+            build("json", Arc::new(JsonReporter::new(buffer.clone())));
+        result.unwrap();
+
+        let events = buffer
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let names = events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            names,
+            vec![
+                "plan",
+                "target_started",
+                "task_started",
+                "task_finished",
+                "target_finished",
+                "target_started",
+                "task_started",
+                "task_finished",
+                "target_finished",
+                "build_finished",
+            ]
+        );
+
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        assert_eq!(
+            events[0],
+            json!({
+                "event": "plan",
+                "targets": [
+                    {
+                        "target": path("a"),
+                        "reason": "no dependencies",
+                        "commands": [format!("touch {}", path("a"))],
+                    },
+                    {
+                        "target": path("b"),
+                        "reason": "output missing",
+                        "commands": [format!("touch {}", path("b"))],
+                    },
+                ],
+                "up_to_date": [],
+            })
+        );
+        assert_eq!(
+            events[1],
+            json!({ "event": "target_started", "target": path("a") })
+        );
+
+        // durations and the working dir vary, check only the field names
+        let fields = |event: &Value| {
+            event
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            fields(&events[2]),
+            vec!["argv", "attempt", "cwd", "event", "target"]
+        );
+        assert_eq!(events[2]["argv"], json!(["touch", path("a")]));
+        assert_eq!(
+            fields(&events[3]),
+            vec![
+                "argv",
+                "attempt",
+                "duration_ms",
+                "event",
+                "exit_code",
+                "target"
+            ]
+        );
+        assert_eq!(events[3]["exit_code"], json!(0));
+        assert_eq!(
+            fields(&events[4]),
+            vec!["duration_ms", "event", "log", "outcome", "target"]
+        );
+        assert_eq!(events[4]["outcome"], json!("built"));
+        assert_eq!(fields(&events[9]), vec!["duration_ms", "event", "success"]);
+        assert_eq!(events[9]["success"], json!(true));
+    }
+}
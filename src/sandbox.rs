@@ -0,0 +1,280 @@
+use std::{
+    env,
+    fs,
+    io,
+    iter::once,
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use crate::{
+    error::{Error, Result},
+    target::{Target, TaskContext},
+};
+
+/// Returns where the sandboxes are created by default, one per worker.
+/// The dir is outside of the project dir so that paths leaving
+/// a sandbox (e.g. `../../src/a.h`) don't lead to the project's files.
+pub fn default_dir() -> PathBuf {
+    env::temp_dir().join(format!("dodo-sandbox-{}", process::id()))
+}
+
+/// A temporary dir mirroring the project dir which contains only links
+/// to target's declared dependencies (directories are mirrored entry
+/// by entry). Target's tasks run in it so that reading an undeclared file
+/// fails, unless it's read by a path leading out of the sandbox (e.g.
+/// an absolute one). Only target's output and depfile are moved back
+/// to the project dir, the rest is removed.
+#[derive(Debug)]
+pub struct Sandbox {
+    /// Absolute path of the sandbox.
+    root: PathBuf,
+    project_dir: PathBuf,
+    /// Target's output and depfile, relative to the project dir.
+    outputs: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Creates the sandbox in `root` (removing anything left there
+    /// by an interrupted build) for the target in `project_dir`.
+    pub fn create(
+        root: PathBuf,
+        project_dir: &Path,
+        target: &Target,
+        context: &TaskContext,
+    ) -> Result<Self> {
+        match fs::remove_dir_all(&root) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound =>
+                return Err(Error::IO(err)),
+            _ => {}
+        }
+        fs::create_dir_all(&root).map_err(Error::IO)?;
+
+        let sandbox = Self {
+            root,
+            project_dir: project_dir.to_path_buf(),
+            outputs: once(target.identifier.clone())
+                .chain(target.depfile_path(context)?)
+                .collect(),
+        };
+
+        // deps outside the project dir stay where they are
+        for dep in target.all_deps().filter_map(|dep| relative(dep)) {
+            sandbox.mirror(&dep)?;
+        }
+
+        let working_dir = target.working_dir().unwrap_or_else(|| "".as_ref());
+        let dirs = once(working_dir.to_path_buf())
+            .chain(target.tasks.iter().filter_map(|task| {
+                task.working_dir().map(|dir| working_dir.join(dir))
+            }))
+            .chain(
+                sandbox
+                    .outputs
+                    .iter()
+                    .filter_map(|output| output.parent())
+                    .map(Path::to_path_buf),
+            )
+            .collect::<Vec<_>>();
+        for dir in dirs.iter().filter_map(|dir| relative(dir)) {
+            fs::create_dir_all(sandbox.root.join(dir)).map_err(Error::IO)?;
+        }
+
+        Ok(sandbox)
+    }
+
+    /// Returns target's working dir inside the sandbox.
+    pub fn working_dir(&self, target: &Target) -> PathBuf {
+        match target.working_dir() {
+            Some(dir) => self.root.join(dir),
+            None => self.root.clone(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Moves the outputs written by target's tasks to the project dir,
+    /// replacing the previous ones.
+    pub fn finish(&self) -> Result<()> {
+        for output in &self.outputs {
+            let built = self.root.join(output);
+            if fs::symlink_metadata(&built).is_err() {
+                continue;
+            }
+
+            let destination = self.project_dir.join(output);
+            remove(&destination)?;
+            if let Some(dir) = destination.parent() {
+                fs::create_dir_all(dir).map_err(Error::IO)?;
+            }
+            move_path(&built, &destination).map_err(Error::IO)?;
+        }
+
+        Ok(())
+    }
+
+    /// Links the file (relative to the project dir) into the sandbox,
+    /// or recreates the directory and mirrors its entries. Outputs
+    /// are skipped so that the tasks can't write through the links,
+    /// and so are missing files (e.g. headers removed since they were
+    /// read from a depfile).
+    fn mirror(&self, path: &Path) -> Result<()> {
+        let source = self.project_dir.join(path);
+        if self.outputs.iter().any(|output| path.starts_with(output)) {
+            return Ok(());
+        }
+
+        let metadata = match fs::metadata(&source) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Error::IO(err)),
+        };
+        let destination = self.root.join(path);
+
+        if metadata.is_dir() {
+            fs::create_dir_all(&destination).map_err(Error::IO)?;
+
+            for entry in fs::read_dir(&source).map_err(Error::IO)? {
+                let entry = entry.map_err(Error::IO)?;
+                self.mirror(&path.join(entry.file_name()))?;
+            }
+        } else if fs::symlink_metadata(&destination).is_err() {
+            if let Some(dir) = destination.parent() {
+                fs::create_dir_all(dir).map_err(Error::IO)?;
+            }
+            link(&source, &destination).map_err(Error::IO)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Returns the path if it's relative and doesn't leave the dir
+/// it's relative to, without the `.` components.
+fn relative(path: &Path) -> Option<PathBuf> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Removes the file or the directory (but not the directory
+/// a symlink points to), if it exists.
+fn remove(path: &Path) -> Result<()> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    };
+
+    removed.map_err(Error::IO)
+}
+
+/// Renames the file or the directory, or copies it if the destination
+/// is on another filesystem (the sandboxes usually are in a tmpfs).
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            copy(from, to)?;
+            match fs::symlink_metadata(from)?.is_dir() {
+                true => fs::remove_dir_all(from),
+                false => fs::remove_file(from),
+            }
+        }
+        moved => moved,
+    }
+}
+
+fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    if !fs::metadata(from)?.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy(&entry.path(), &to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn link(source: &Path, destination: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source, destination)
+}
+
+// creating symlinks requires a privilege on Windows
+#[cfg(not(unix))]
+fn link(source: &Path, destination: &Path) -> io::Result<()> {
+    fs::copy(source, destination).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::TempDir;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_sandbox() {
+        let dir = TempDir::new("project");
+        let sandboxes = TempDir::new("sandbox");
+        for file in ["src/a.c", "src/a.h", "b.h", "undeclared", "out/old"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+
+        let target = Target {
+            identifier: "out/old".into(),
+            deps: vec!["src".into(), "../outside".into()],
+            order_deps: vec!["out".into(), "./b.h".into()],
+            depfile: Some("{target}.d".into()),
+            ..Default::default()
+        };
+        let context = target.task_context().unwrap();
+        let sandbox =
+            Sandbox::create(sandboxes.join("0"), &dir, &target, &context)
+                .unwrap();
+        let root = sandbox.root().to_path_buf();
+        let read = |path: &str| fs::read_to_string(root.join(path)).ok();
+
+        assert_eq!(read("src/a.h").as_deref(), Some("src/a.h"));
+        assert_eq!(read("b.h").as_deref(), Some("b.h"));
+        assert_eq!(read("undeclared"), None);
+        // leaving the sandbox doesn't lead to the project dir
+        assert!(!root.starts_with(&dir));
+        assert_eq!(read("../undeclared"), None);
+        assert_eq!(read("../../undeclared"), None);
+        // the previous output isn't linked
+        assert_eq!(read("out/old"), None);
+        assert!(root.join("out").is_dir());
+
+        fs::write(root.join("out/old"), "new").unwrap();
+        fs::write(root.join("out/old.d"), "deps").unwrap();
+        fs::write(root.join("out/tmp"), "").unwrap();
+        sandbox.finish().unwrap();
+        drop(sandbox);
+
+        let read = |path: &str| fs::read_to_string(dir.join(path)).ok();
+        let results = (read("out/old"), read("out/old.d"), read("out/tmp"));
+        let removed = !root.exists();
+
+        assert_eq!(results, (Some("new".into()), Some("deps".into()), None));
+        assert!(removed);
+    }
+}
@@ -0,0 +1,507 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    hash::{self, HashAlgorithm},
+    target::{serde_duration, Discover, Target},
+};
+
+use serde::*;
+
+pub const LOCK_FILE: &str = "dodo.lock";
+
+/// Build state persisted between runs in `dodo.lock`.
+///
+/// For every target that has been built successfully it stores
+/// the state of the files the target depended on at that time
+/// and how long the build took.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct BuildState {
+    #[serde(skip)]
+    path: PathBuf,
+    /// How the files are hashed, configured in `dodo.toml`.
+    #[serde(skip)]
+    hash: HashAlgorithm,
+    /// How the files are compared, configured in `dodo.toml`.
+    #[serde(skip)]
+    staleness: Staleness,
+    #[serde(default)]
+    targets: BTreeMap<PathBuf, TargetState>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    discoveries: BTreeMap<PathBuf, DiscoveryState>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct TargetState {
+    // values have to precede tables in TOML
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_duration"
+    )]
+    pub duration: Option<Duration>,
+    /// Dependencies read from target's depfile.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discovered_deps: Vec<PathBuf>,
+    #[serde(default)]
+    pub deps: BTreeMap<PathBuf, FileState>,
+}
+
+/// The outcome of the last discovery of target's dependencies.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct DiscoveryState {
+    pub command: String,
+    pub discovered_deps: Vec<PathBuf>,
+    /// The state of discovery's inputs.
+    #[serde(default)]
+    pub deps: BTreeMap<PathBuf, FileState>,
+}
+
+/// How it's determined whether a file has been modified since
+/// the last build.
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq, Default,
+)]
+pub enum Staleness {
+    /// Compare the size and the modification time. Cheap but changes
+    /// may be missed on filesystems with coarse timestamps.
+    #[serde(rename = "mtime")]
+    Mtime,
+    /// Compare the hash of the content.
+    #[serde(rename = "hash")]
+    Hash,
+    /// Compare the size and the modification time and if they differ,
+    /// compare the hash (so that e.g. touched files aren't rebuilt).
+    #[default]
+    #[serde(rename = "mtime+hash")]
+    MtimeHash,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct FileState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Nanoseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+impl FileState {
+    /// Returns the size and the modification time of the file
+    /// (without the hash).
+    fn metadata(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path).map_err(Error::IO)?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_nanos() as u64);
+
+        Ok(Self {
+            hash: None,
+            size: Some(metadata.len()),
+            mtime,
+        })
+    }
+
+    fn same_metadata(&self, other: &FileState) -> bool {
+        self.size.is_some()
+            && self.mtime.is_some()
+            && self.size == other.size
+            && self.mtime == other.mtime
+    }
+
+    fn same_hash(&self, other: &FileState) -> bool {
+        self.hash.is_some() && self.hash == other.hash
+    }
+
+    fn needs_hash(
+        &self,
+        previous_state: &FileState,
+        staleness: Staleness,
+    ) -> bool {
+        match staleness {
+            Staleness::Mtime => false,
+            Staleness::Hash => true,
+            Staleness::MtimeHash => !self.same_metadata(previous_state),
+        }
+    }
+
+    /// Whether the file (in this state) hasn't been modified since
+    /// it was in `previous_state`.
+    pub fn matches(
+        &self,
+        previous_state: &FileState,
+        staleness: Staleness,
+    ) -> bool {
+        match staleness {
+            Staleness::Mtime => self.same_metadata(previous_state),
+            Staleness::Hash => self.same_hash(previous_state),
+            Staleness::MtimeHash =>
+                self.same_metadata(previous_state)
+                    || self.same_hash(previous_state),
+        }
+    }
+}
+
+impl BuildState {
+    /// Loads the state from `path`. A missing file is treated
+    /// as an empty state (nothing has been built yet).
+    pub fn load(
+        path: impl AsRef<Path>,
+        hash: HashAlgorithm,
+        staleness: Staleness,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+
+        let mut state = match fs::read_to_string(path) {
+            Ok(content) =>
+                toml::from_str::<Self>(&content).map_err(Error::TOML)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+                Self::default(),
+            Err(e) => return Err(Error::IO(e)),
+        };
+        state.path = path.to_path_buf();
+        state.hash = hash;
+        state.staleness = staleness;
+
+        Ok(state)
+    }
+
+    /// Saves the state. The file is replaced atomically so it stays
+    /// consistent even if dodo gets killed in the meantime.
+    pub fn save(&self) -> Result<()> {
+        let content = toml::to_string(self)
+            .map_err(|_| Error::internal(line!(), file!()))?;
+        let tmp_path = self.path.with_extension("lock.tmp");
+
+        fs::write(&tmp_path, content).map_err(Error::IO)?;
+        fs::rename(&tmp_path, &self.path).map_err(Error::IO)
+    }
+
+    pub fn staleness(&self) -> Staleness {
+        self.staleness
+    }
+
+    pub fn target(&self, identifier: &Path) -> Option<&TargetState> {
+        self.targets.get(identifier)
+    }
+
+    /// Sets the dependencies discovered during the last build
+    /// of the targets, read from their depfiles or recorded
+    /// by tracing their tasks.
+    pub fn add_discovered_deps(&self, targets: &mut [Target]) {
+        for target in targets.iter_mut() {
+            if let Some(target_state) = self.target(&target.identifier) {
+                target.discovered_deps = target_state.discovered_deps.clone();
+            }
+        }
+    }
+
+    /// Returns the current state of the files. Each file comes with
+    /// its recorded state (if any) to decide whether it has to be hashed
+    /// to be compared with it. Files are hashed in parallel.
+    pub fn file_states<'a>(
+        &self,
+        files: impl IntoIterator<Item = (&'a PathBuf, Option<&'a FileState>)>,
+    ) -> Result<HashMap<PathBuf, FileState>> {
+        let mut states = HashMap::new();
+        let mut to_hash = HashSet::new();
+
+        for (path, previous_state) in files {
+            if !states.contains_key(path) {
+                states.insert(path.clone(), FileState::metadata(path)?);
+            }
+
+            if let Some(previous_state) = previous_state {
+                if states[path].needs_hash(previous_state, self.staleness) {
+                    to_hash.insert(path);
+                }
+            }
+        }
+
+        self.add_hashes(&mut states, to_hash)?;
+
+        Ok(states)
+    }
+
+    fn add_hashes<'a>(
+        &self,
+        states: &mut HashMap<PathBuf, FileState>,
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> Result<()> {
+        for (path, hash) in hash::hash_files(paths, self.hash)? {
+            if let Some(state) = states.get_mut(&path) {
+                state.hash = Some(hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the recorded state of target's dependencies which have
+    /// been touched but not modified with their current state, so that
+    /// they aren't hashed again. Returns whether any has been replaced.
+    pub fn refresh(
+        &mut self,
+        identifier: &Path,
+        current_states: &HashMap<PathBuf, FileState>,
+    ) -> bool {
+        let staleness = self.staleness;
+        let target_state = match self.targets.get_mut(identifier) {
+            Some(target_state) => target_state,
+            None => return false,
+        };
+        let mut refreshed = false;
+
+        for (dep, previous_state) in &mut target_state.deps {
+            match current_states.get(dep) {
+                // without the hash it can't be told whether it's unchanged
+                Some(state)
+                    if state.hash.is_some()
+                        && state != previous_state
+                        && state.matches(previous_state, staleness) =>
+                {
+                    *previous_state = state.clone();
+                    refreshed = true;
+                }
+                _ => {}
+            }
+        }
+
+        refreshed
+    }
+
+    pub fn forget(&mut self, identifier: &Path) {
+        self.targets.remove(identifier);
+        self.discoveries.remove(identifier);
+    }
+
+    /// Records the current state of target's dependencies and the duration
+    /// of the build. Should be called after the target has been built
+    /// successfully.
+    /// Dependencies which are not files (e.g. targets which don't
+    /// produce any output) are skipped.
+    pub fn record<'a>(
+        &mut self,
+        identifier: &Path,
+        deps: impl IntoIterator<Item = &'a PathBuf>,
+        discovered_deps: Vec<PathBuf>,
+        duration: Duration,
+    ) -> Result<()> {
+        let deps = self.current_states(
+            deps.into_iter()
+                .cloned()
+                .chain(discovered_deps.iter().cloned()),
+        )?;
+
+        let target_state = TargetState {
+            duration: Some(duration),
+            discovered_deps,
+            deps,
+        };
+        self.targets.insert(identifier.to_path_buf(), target_state);
+
+        Ok(())
+    }
+
+    /// Returns the dependencies found by the last discovery
+    /// of the target, unless its command or its inputs have changed
+    /// since then.
+    pub fn discovered_deps(
+        &self,
+        identifier: &Path,
+        discover: &Discover,
+    ) -> Result<Option<&[PathBuf]>> {
+        let discovery = match self.discoveries.get(identifier) {
+            Some(discovery) if discovery.command == discover.command =>
+                discovery,
+            _ => return Ok(None),
+        };
+
+        let inputs = discover
+            .deps
+            .iter()
+            .filter(|dep| dep.is_file())
+            .collect::<Vec<_>>();
+        if inputs.len() != discovery.deps.len() {
+            return Ok(None);
+        }
+
+        let previous_states = inputs
+            .iter()
+            .map(|dep| discovery.deps.get(*dep).map(|state| (*dep, state)))
+            .collect::<Option<Vec<_>>>();
+        let previous_states = match previous_states {
+            Some(previous_states) => previous_states,
+            None => return Ok(None),
+        };
+
+        let current_states = self.file_states(
+            previous_states
+                .iter()
+                .map(|(dep, state)| (*dep, Some(*state))),
+        )?;
+        let unchanged = previous_states.iter().all(|(dep, state)| {
+            current_states[*dep].matches(state, self.staleness)
+        });
+
+        Ok(unchanged.then_some(discovery.discovered_deps.as_slice()))
+    }
+
+    /// Returns the dependencies found by the last discovery of the target
+    /// (even if its inputs have changed since then), unless its command
+    /// has changed.
+    pub fn last_discovered_deps(
+        &self,
+        identifier: &Path,
+        discover: &Discover,
+    ) -> Option<&[PathBuf]> {
+        self.discoveries
+            .get(identifier)
+            .filter(|discovery| discovery.command == discover.command)
+            .map(|discovery| discovery.discovered_deps.as_slice())
+    }
+
+    /// Records the dependencies found by the discovery of the target
+    /// along with the current state of discovery's inputs.
+    pub fn record_discovery(
+        &mut self,
+        identifier: &Path,
+        discover: &Discover,
+        discovered_deps: Vec<PathBuf>,
+    ) -> Result<()> {
+        let deps = self.current_states(discover.deps.iter().cloned())?;
+
+        let discovery = DiscoveryState {
+            command: discover.command.clone(),
+            discovered_deps,
+            deps,
+        };
+        self.discoveries.insert(identifier.to_path_buf(), discovery);
+
+        Ok(())
+    }
+
+    /// Returns the state of the files, hashed unless only modification
+    /// times are compared. Files which don't exist (or aren't files)
+    /// are skipped.
+    fn current_states(
+        &self,
+        files: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<BTreeMap<PathBuf, FileState>> {
+        let mut states = files
+            .into_iter()
+            .filter(|file| file.is_file())
+            .map(|file| {
+                let state = FileState::metadata(&file)?;
+
+                Ok((file, state))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        // the hash is only needed if it's going to be compared
+        if self.staleness != Staleness::Mtime {
+            let paths = states.keys().cloned().collect::<Vec<_>>();
+            self.add_hashes(&mut states, &paths)?;
+        }
+
+        Ok(states.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::test_util::TempDir;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_file_state_matches() {
+        let state = |hash: &str, size, mtime| FileState {
+            hash: Some(hash.to_string()),
+            size: Some(size),
+            mtime: Some(mtime),
+        };
+        let previous = state("a", 3, 100);
+        let touched = state("a", 3, 200);
+        let modified = state("b", 3, 200);
+        // e.g. modified within the timestamp's resolution
+        let same_mtime = state("b", 3, 100);
+
+        use Staleness::*;
+
+        assert!(previous.matches(&previous, Mtime));
+        assert!(!touched.matches(&previous, Mtime));
+        assert!(same_mtime.matches(&previous, Mtime));
+
+        assert!(touched.matches(&previous, Hash));
+        assert!(!same_mtime.matches(&previous, Hash));
+
+        assert!(touched.matches(&previous, MtimeHash));
+        assert!(!modified.matches(&previous, MtimeHash));
+        assert!(!touched.needs_hash(&touched, MtimeHash));
+        assert!(touched.needs_hash(&previous, MtimeHash));
+
+        // states recorded by older versions have no metadata
+        let legacy = FileState {
+            hash: Some("a".to_string()),
+            ..Default::default()
+        };
+        assert!(!touched.matches(&legacy, Mtime));
+        assert!(touched.matches(&legacy, MtimeHash));
+    }
+    #[test]
+    fn test_record_save_load() {
+        let dir = TempDir::new("state");
+        let lock_file = dir.join("dodo.lock");
+        let (src, missing) = (dir.join("src"), dir.join("missing"));
+        fs::write(&src, "a").unwrap();
+        let load = || {
+            BuildState::load(
+                &lock_file,
+                HashAlgorithm::default(),
+                Staleness::Hash,
+            )
+            .unwrap()
+        };
+
+        let mut state = load();
+        assert!(state.target(&dir.join("out")).is_none());
+        state
+            .record(
+                &dir.join("out"),
+                &[src.clone(), missing],
+                vec![dir.join("header")],
+                Duration::from_secs(3),
+            )
+            .unwrap();
+        state.save().unwrap();
+
+        let state = load();
+        let target_state = state.target(&dir.join("out")).unwrap();
+        assert_eq!(target_state.duration, Some(Duration::from_secs(3)));
+        assert_eq!(target_state.discovered_deps, vec![dir.join("header")]);
+        // files which don't exist are skipped
+        let deps = target_state.deps.keys().collect::<Vec<_>>();
+        assert_eq!(deps, vec![&src]);
+
+        let unchanged = |state: &BuildState| {
+            let previous = &state.target(&dir.join("out")).unwrap().deps[&src];
+            let current = state.file_states([(&src, Some(previous))]).unwrap();
+            current[&src].matches(previous, Staleness::Hash)
+        };
+        assert!(unchanged(&state));
+        fs::write(&src, "b").unwrap();
+        assert!(!unchanged(&state));
+    }
+}
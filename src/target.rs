@@ -1,32 +1,106 @@
 use std::{
+    collections::BTreeMap,
     convert::AsRef,
+    env,
+    iter::once,
     path::{Path, PathBuf},
-    process::{Command, ExitStatus},
+    process::ExitStatus,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    cache::CacheConfig,
+    depfile,
     error::{Error, Result, UserError},
+    executor::{Executor, Process, TaskSpec, POLL_INTERVAL},
+    hash::HashAlgorithm,
+    isolation::Isolation,
+    log::TargetLog,
+    state::Staleness,
     util::format_arg,
 };
 
 use dynfmt::FormatArgs;
 use serde::*;
 
+pub const CONFIG_FILE: &str = "dodo.toml";
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     // env vars?
+    /// How files are hashed to detect changes and compute cache keys.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// How it's determined whether dependencies have been modified.
+    #[serde(default)]
+    pub staleness: Staleness,
     pub targets: Vec<Target>,
+    #[serde(default)]
+    pub cache: CacheConfig,
 }
 
 // waiting for https://github.com/serde-rs/serde/issues/939
 // to add validation
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Target {
     #[serde(rename = "target")]
     pub identifier: PathBuf, // handle multiple outputs?
     pub tasks: Vec<Task>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub working_dir: Option<PathBuf>,
+    /// A directory only has to exist, changes to its content don't make
+    /// the target obsolete.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deps: Vec<PathBuf>,
+    /// Dependencies which have to be built before the target but don't
+    /// make it obsolete when they change, e.g. output directories.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order_deps: Vec<PathBuf>,
+    /// Makefile-style depfile written by target's tasks (e.g. by `gcc -MD`),
+    /// listing additional dependencies. Supports `{target}`
+    /// and `{target_filename}` placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depfile: Option<String>,
+    /// Dependencies read from the depfile during the last build.
+    #[serde(skip)]
+    pub discovered_deps: Vec<PathBuf>,
+    /// Dependencies printed by the discover task.
+    #[serde(skip)]
+    pub dynamic_deps: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Default timeout for target's tasks.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_duration"
+    )]
+    pub timeout: Option<Duration>,
+    /// Task printing additional dependencies, run before deciding
+    /// whether the target is obsolete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discover: Option<Discover>,
+    /// Whether the tasks run in a sandbox containing only the declared
+    /// dependencies, so that the undeclared ones make them fail.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub sandbox: bool,
+    /// Whether the tasks run without network access and with the project
+    /// dir read-only, except for the dirs of the output and the depfile
+    /// (or the sandbox). Linux only.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub isolated: bool,
+}
+
+/// A command printing target's dependencies, either one per line
+/// or as a JSON array. The paths are relative to target's working dir.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct Discover {
+    pub command: String,
+    /// Files the discovery depends on, built first if they're targets.
+    /// It's only re-run when any of them (or the command) changes,
+    /// so without them it runs once.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub deps: Vec<PathBuf>,
 }
@@ -35,17 +109,113 @@ impl Target {
     pub fn working_dir(&self) -> Option<&Path> {
         self.working_dir.as_ref().map(|d| d.as_ref())
     }
+
+    /// Returns the regular, the discovered, the dynamic
+    /// and the order-only dependencies.
+    pub fn all_deps(&self) -> impl Iterator<Item = &PathBuf> {
+        self.deps
+            .iter()
+            .chain(&self.discovered_deps)
+            .chain(&self.dynamic_deps)
+            .chain(&self.order_deps)
+    }
+
+    /// Returns the path of target's depfile with the placeholders replaced.
+    pub fn depfile_path(
+        &self,
+        context: &TaskContext,
+    ) -> Result<Option<PathBuf>> {
+        self.depfile
+            .as_ref()
+            .map(|depfile| format_arg(depfile, context).map(PathBuf::from))
+            .transpose()
+    }
+
+    /// Reads the dependencies listed in target's depfile (if it has any),
+    /// except for the ones which are already declared. The depfile
+    /// is relative to the current dir, like the target, while the paths
+    /// inside it are relative to target's working dir, where the tasks
+    /// writing it run.
+    pub fn read_depfile(&self, context: &TaskContext) -> Result<Vec<PathBuf>> {
+        let depfile = match self.depfile_path(context)? {
+            Some(depfile) => depfile,
+            None => return Ok(vec![]),
+        };
+
+        let deps = depfile::read(&depfile)?
+            .into_iter()
+            .map(|dep| match self.working_dir() {
+                Some(dir) => dir.join(dep),
+                None => dep,
+            })
+            .filter(|dep| *dep != self.identifier && !self.deps.contains(dep))
+            .collect();
+
+        Ok(deps)
+    }
+
+    /// Returns target's working dir joined with the current dir.
+    pub fn resolve_working_dir(&self) -> Result<PathBuf> {
+        let current_dir = env::current_dir().map_err(Error::IO)?;
+
+        Ok(match self.working_dir() {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir,
+        })
+    }
+
+    pub fn task_context(&self) -> Result<TaskContext> {
+        let target_filename = self
+            .identifier
+            .file_name()
+            .ok_or(UserError::EmptyTargetIdentifier)?
+            .to_str()
+            .ok_or_else(|| Error::internal(line!(), file!()))? // TOML uses UTF-8 so the conversion won't fail
+            .to_string();
+
+        Ok(TaskContext {
+            target: self.identifier.to_string_lossy().to_string(),
+            target_filename,
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, Default)]
 pub struct Task {
     pub command: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub working_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_duration"
+    )]
+    pub timeout: Option<Duration>,
+    /// How many times the task is retried if it fails or times out.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// Delay before the first retry, doubled after each attempt.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_duration"
+    )]
+    pub backoff: Option<Duration>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
 }
 
 #[derive(Clone)]
 pub struct TaskContext {
+    pub target: String,
     pub target_filename: String,
 }
 
@@ -53,8 +223,9 @@ impl FormatArgs for TaskContext {
     fn get_key(
         &self,
         key: &str,
-    ) -> std::result::Result<Option<dynfmt::Argument>, ()> {
+    ) -> std::result::Result<Option<dynfmt::Argument<'_>>, ()> {
         match key {
+            "target" => Ok(Some(&self.target)),
             "target_filename" => Ok(Some(&self.target_filename)),
             _ => Ok(None),
         }
@@ -66,6 +237,34 @@ impl Task {
         self.working_dir.as_ref().map(|d| d.as_ref())
     }
 
+    pub fn resolve_working_dir(&self, target_working_dir: PathBuf) -> PathBuf {
+        self.working_dir()
+            .map(|subdir| target_working_dir.join(subdir))
+            .unwrap_or(target_working_dir)
+    }
+
+    /// Returns target's env extended (and overriden) with task's env.
+    pub fn resolve_env(
+        &self,
+        target_env: &BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        target_env
+            .iter()
+            .chain(self.env.iter())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Returns the delay before retrying the task after
+    /// the `attempt`-th attempt (counting from 1) has failed.
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+
+        self.backoff
+            .map(|backoff| backoff.saturating_mul(factor))
+            .unwrap_or_default()
+    }
+
     pub fn format_command(
         &self,
         context: impl FormatArgs,
@@ -73,32 +272,113 @@ impl Task {
         let mut parts = self.command.split(' ').filter(|s| !s.is_empty());
         let command = parts.nth(0).ok_or(UserError::EmptyCommand)?.to_string();
         let args = parts
-            .map(|arg| format_arg(arg, &context).map_err(Error::from))
+            .map(|arg| format_arg(arg, &context))
             .collect::<Result<_>>()?;
 
         Ok((command, args))
     }
 
+    /// Runs the task with the executor and waits for it to finish. Task's
+    /// output is captured to the `log`. The task (along with its children)
+    /// is terminated if it doesn't finish within `timeout` or as soon
+    /// as `cancel` is set.
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         &self,
+        executor: &dyn Executor,
         target_working_dir: PathBuf,
+        target_env: &BTreeMap<String, String>,
+        isolation: Option<&Isolation>,
         context: impl FormatArgs,
+        timeout: Option<Duration>,
+        log: &TargetLog,
+        cancel: &AtomicBool,
     ) -> Result<ExitStatus> {
-        let working_dir = self
-            .working_dir()
-            .map(|subdir| target_working_dir.join(subdir))
-            .unwrap_or(target_working_dir);
-
         let (command, args) = self.format_command(context)?;
 
-        println!("executing: {} {}", command, args.join(" "));
+        log.write_header(&format!("$ {} {}", command, args.join(" ")))?;
+
+        let spec = TaskSpec {
+            argv: once(command).chain(args).collect(),
+            env: self.resolve_env(target_env),
+            cwd: self.resolve_working_dir(target_working_dir),
+            isolation: isolation.cloned(),
+        };
+        let mut process = executor.spawn(&spec, log)?;
+        let result = self.wait(process.as_mut(), timeout, cancel);
+
+        process.finish()?;
+
+        result
+    }
+
+    fn wait(
+        &self,
+        process: &mut dyn Process,
+        timeout: Option<Duration>,
+        cancel: &AtomicBool,
+    ) -> Result<ExitStatus> {
+        let started = Instant::now();
+
+        loop {
+            if let Some(status) = process.try_wait()? {
+                return Ok(status);
+            }
+
+            if cancel.load(Ordering::SeqCst) {
+                process.terminate()?;
+
+                return Err(Error::Cancelled);
+            }
+
+            if let Some(timeout) = timeout {
+                if started.elapsed() >= timeout {
+                    process.terminate()?;
+
+                    return Err(Error::TimedOut {
+                        command: self.command.clone(),
+                        timeout,
+                    });
+                }
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// (De)serializes durations like `"30s"` or `"500ms"`.
+pub mod serde_duration {
+    use std::time::Duration;
+
+    use crate::util::{format_duration, parse_duration};
+
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match duration {
+            Some(duration) =>
+                serializer.serialize_str(&format_duration(*duration)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
 
-        Command::new(command)
-            .current_dir(working_dir)
-            .args(&args)
-            .spawn()
-            .map(|mut child| child.wait())
-            .flatten()
-            .map_err(Error::IO)
+        parse_duration(&text).map(Some).ok_or_else(|| {
+            D::Error::custom(format!("invalid duration `{}`", text))
+        })
     }
 }
@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use crate::target::Target;
+
+/// What's done with the files the tasks access without their targets
+/// declaring them. Requires an executor tracing the tasks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum TraceDeps {
+    #[default]
+    Off,
+    /// Report the undeclared inputs and outputs.
+    Report,
+    /// Report them and add the undeclared inputs to the build state,
+    /// like the ones read from depfiles.
+    Record,
+}
+
+/// Files opened by a task (and its children), with absolute paths.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FileAccesses {
+    pub read: BTreeSet<PathBuf>,
+    pub written: BTreeSet<PathBuf>,
+}
+
+impl FileAccesses {
+    pub fn extend(&mut self, other: FileAccesses) {
+        self.read.extend(other.read);
+        self.written.extend(other.written);
+    }
+}
+
+/// Files inside the project dir accessed by target's tasks
+/// which the target doesn't declare, relative to the project dir.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Undeclared {
+    /// Files read but neither declared as dependencies
+    /// nor written by the tasks beforehand.
+    pub inputs: Vec<PathBuf>,
+    /// Files written other than the target.
+    pub outputs: Vec<PathBuf>,
+}
+
+impl Undeclared {
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty() && self.outputs.is_empty()
+    }
+}
+
+/// Compares the files accessed by target's tasks with its dependencies
+/// and its output. Files outside `project_dir` (e.g. compilers or system
+/// headers), files which don't exist anymore and files in the `ignored`
+/// dirs (relative to `project_dir`) are skipped.
+pub fn undeclared(
+    target: &Target,
+    accesses: &FileAccesses,
+    project_dir: &Path,
+    ignored: &[&Path],
+) -> Undeclared {
+    let relative = |path: &PathBuf| {
+        let relative = path.strip_prefix(project_dir).ok()?;
+        let skip = !path.is_file()
+            || ignored.iter().any(|dir| relative.starts_with(dir));
+
+        (!skip).then(|| relative.to_path_buf())
+    };
+
+    let inputs = accesses
+        .read
+        .difference(&accesses.written)
+        .filter_map(relative)
+        .filter(|path| *path != target.identifier)
+        // files in declared directories are declared too
+        .filter(|path| target.all_deps().all(|dep| !path.starts_with(dep)))
+        .collect();
+    let outputs = accesses
+        .written
+        .iter()
+        .filter_map(relative)
+        .filter(|path| !path.starts_with(&target.identifier))
+        .collect();
+
+    Undeclared { inputs, outputs }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use crate::test_util::TempDir;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_undeclared() {
+        let dir = TempDir::new("undeclared");
+        for file in [
+            "a.c",
+            "a.h",
+            "b.h",
+            "inc/c.h",
+            "tmp.o",
+            "out",
+            "extra",
+            ".dodo/log",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let target = Target {
+            identifier: "out".into(),
+            deps: vec!["a.c".into(), "inc".into()],
+            order_deps: vec!["b.h".into()],
+            ..Default::default()
+        };
+        let paths =
+            |paths: &[&str]| paths.iter().map(|path| dir.join(path)).collect();
+        let accesses = FileAccesses {
+            read: paths(&[
+                "a.c", "a.h", "b.h", "inc/c.h", "tmp.o", "gone", "/usr/x.h",
+            ]),
+            written: paths(&["tmp.o", "out", "extra", ".dodo/log"]),
+        };
+
+        let found = undeclared(&target, &accesses, &dir, &[".dodo".as_ref()]);
+
+        assert_eq!(
+            found,
+            Undeclared {
+                inputs: vec!["a.h".into()],
+                outputs: vec!["extra".into(), "tmp.o".into()],
+            }
+        );
+    }
+}
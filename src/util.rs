@@ -1,22 +1,44 @@
 use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     convert::AsRef,
     env,
     fs,
-    hash::{Hash, Hasher as _},
     iter::once,
-    path::Path,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     result::Result as StdResult,
     string::ToString,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    cache::Cache,
+    deps::{Plan, Reason},
     error::{Error, Result, UserError},
-    target::{Target, TaskContext},
+    event::{self, Event},
+    executor::{Executor, LocalExecutor},
+    hash::{self, HashAlgorithm},
+    isolation::Isolation,
+    log::{OutputMode, TargetLog, LOG_DIR},
+    profile::{Profiler, WorkerProfiler},
+    progress::Outcome,
+    report::{Reporter, TerminalReporter},
+    sandbox::{self, Sandbox},
+    state::{BuildState, Staleness},
+    target::{Config, Target, Task, TaskContext},
+    trace::{self, TraceDeps},
 };
 
 use ansi_term::Colour::*;
 use dynfmt::{Format, FormatArgs, SimpleCurlyFormat as Formatter};
-use twox_hash::XxHash64 as Hasher;
+use serde_json::json;
 
 pub fn format_arg(arg: &str, context: impl FormatArgs) -> Result<String> {
     Formatter
@@ -25,14 +47,29 @@ pub fn format_arg(arg: &str, context: impl FormatArgs) -> Result<String> {
         .map(|cow| cow.to_string())
 }
 
-pub fn get_file_hash(path: impl AsRef<Path>) -> Result<u64> {
-    let mut hasher = Hasher::default();
+/// Parses durations like `"30s"`, `"1.5m"` or `"500ms"`.
+/// Supported units are `ms`, `s`, `m` and `h`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split_at = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (value, unit) = text.split_at(split_at);
+    let value = value.parse::<f64>().ok()?;
 
-    fs::read(path.as_ref()).map_err(Error::IO).map(|content| {
-        content.hash(&mut hasher);
+    let seconds = match unit.trim() {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return None,
+    };
 
-        hasher.finish()
-    })
+    Some(Duration::from_secs_f64(seconds))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
 }
 
 pub fn read_config<P>(file: P) -> Result<String>
@@ -49,7 +86,19 @@ where
     })
 }
 
-pub fn print_targets(targets: &[Target]) -> Result<()> {
+pub fn load_config<P>(file: P) -> Result<Config>
+where
+    P: AsRef<Path>,
+{
+    let config = read_config(file)?;
+
+    toml::from_str::<Config>(&config).map_err(Error::TOML)
+}
+
+pub fn print_targets(
+    targets: &[Target],
+    algorithm: HashAlgorithm,
+) -> Result<()> {
     for target in targets {
         println!(
             "{}: {}",
@@ -66,8 +115,7 @@ pub fn print_targets(targets: &[Target]) -> Result<()> {
                 .to_string_lossy()
         );
 
-        let hash = get_file_hash(&target.identifier)
-            .map(|h| format!("{:x}", h))
+        let hash = hash::hash_file(&target.identifier, algorithm)
             .unwrap_or_else(|_| "file not present".into());
         println!("{}: {}", Green.paint("HASH"), hash);
 
@@ -78,7 +126,10 @@ pub fn print_targets(targets: &[Target]) -> Result<()> {
             .to_str()
             .ok_or_else(|| Error::internal(line!(), file!()))?; // TOML uses UTF-8 so the conversion won't fail
         let target_filename = Fixed(14).paint(target_filename).to_string();
-        let context = TaskContext { target_filename };
+        let context = TaskContext {
+            target: target.identifier.to_string_lossy().to_string(),
+            target_filename,
+        };
 
         println!("{}:", Green.paint("COMMANDS"));
         target
@@ -108,88 +159,739 @@ pub fn print_targets(targets: &[Target]) -> Result<()> {
     Ok(())
 }
 
-pub fn run_targets(targets: Vec<Target>) -> Result<()> {
-    let targets_with_contexts = targets
+/// Prints the targets that would be built, in order, along with
+/// the reason, resolved working dirs and env of their tasks.
+pub fn print_plan(targets: &[(Target, Reason)]) -> Result<()> {
+    if targets.is_empty() {
+        println!("{}", Green.paint("UP TO DATE"));
+    }
+
+    for (target, reason) in targets {
+        println!(
+            "{}: {}",
+            Green.paint("OUTPUT"),
+            target.identifier.to_string_lossy()
+        );
+        println!("{}: {}", Green.paint("REASON"), reason);
+
+        let working_dir = target.resolve_working_dir()?;
+        let context = target.task_context()?;
+
+        println!("{}:", Green.paint("COMMANDS"));
+        for task in &target.tasks {
+            let (command, args) = task.format_command(&context)?;
+            let line = once(command).chain(args).collect::<Vec<_>>().join(" ");
+            let dir = task.resolve_working_dir(working_dir.clone());
+            let dir =
+                Fixed(242).paint(format!("# in {}", dir.to_string_lossy()));
+            println!("$ {} {}", line, dir);
+
+            for (key, value) in task.resolve_env(&target.env) {
+                println!(
+                    "  {}",
+                    Fixed(242).paint(format!("{}={}", key, value))
+                );
+            }
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Removes targets' outputs and forgets their state, along with the other
+/// files and dirs (e.g. logs). Sources (`NoRule` nodes) are never touched
+/// since they are not targets. A dry run only prints what would be removed.
+pub fn clean_targets(
+    targets: &[&Target],
+    others: &[PathBuf],
+    state: &mut BuildState,
+    dry_run: bool,
+) -> Result<()> {
+    let print_removal = |path: &Path| {
+        let label = match dry_run {
+            true => Yellow.paint("WOULD REMOVE"),
+            false => Green.paint("REMOVE"),
+        };
+        println!("{}: {}", label, path.to_string_lossy());
+    };
+
+    for target in targets {
+        let output = &target.identifier;
+
+        if output.is_file() {
+            print_removal(output);
+
+            if !dry_run {
+                fs::remove_file(output).map_err(Error::IO)?;
+            }
+        } else if output.is_dir() {
+            let message = format!(
+                "# {} is a directory, skipping",
+                output.to_string_lossy()
+            );
+            println!("{}", Fixed(242).paint(message));
+        }
+
+        if !dry_run {
+            state.forget(output);
+        }
+    }
+
+    for path in others {
+        let is_dir = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => continue,
+        };
+        print_removal(path);
+
+        if !dry_run {
+            let removed = match is_dir {
+                true => fs::remove_dir_all(path),
+                false => fs::remove_file(path),
+            };
+            removed.map_err(Error::IO)?;
+        }
+    }
+
+    if !dry_run {
+        state.save()?;
+    }
+
+    Ok(())
+}
+
+/// Prints the chain of targets which took the longest to build.
+pub fn print_critical_path(path: &[(PathBuf, Option<Duration>)]) {
+    let total = path
+        .iter()
+        .filter_map(|(_, duration)| *duration)
+        .sum::<Duration>();
+
+    println!(
+        "{}: {:.1}s",
+        Green.paint("CRITICAL PATH"),
+        total.as_secs_f64()
+    );
+
+    for (target, duration) in path {
+        let duration = match duration {
+            Some(duration) => format!("{:.1}s", duration.as_secs_f64()),
+            None => Fixed(242).paint("never built").to_string(),
+        };
+
+        println!("  {} {}", target.to_string_lossy(), duration);
+    }
+}
+
+/// Which of the targets ready to be built are started first.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Schedule {
+    /// In the order of the plan.
+    Plan,
+    /// Targets on the critical path first, i.e. targets with the
+    /// longest (recorded) chain of planned targets depending on them.
+    CriticalPath,
+}
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Timeout for tasks which don't specify one (neither does
+    /// their target).
+    pub timeout: Option<Duration>,
+    pub output: OutputMode,
+    /// How many targets can be built at the same time.
+    pub jobs: usize,
+    /// Receives the events of the build.
+    pub reporter: Arc<dyn Reporter>,
+    /// Where to write the trace of the build (in the Chrome
+    /// trace event format).
+    pub profile: Option<PathBuf>,
+    pub schedule: Schedule,
+    /// Where targets' outputs are stored and restored from.
+    pub cache: Option<Cache>,
+    /// How files are hashed, configured in `dodo.toml`.
+    pub hash: HashAlgorithm,
+    /// How files are compared, configured in `dodo.toml`.
+    pub staleness: Staleness,
+    /// Runs the tasks.
+    pub executor: Arc<dyn Executor>,
+    /// Where targets' logs are written.
+    pub log_dir: PathBuf,
+    /// Where sandboxed targets are built.
+    pub sandbox_dir: PathBuf,
+    /// What's done with the files accessed by tasks without being
+    /// declared, if the executor traces them.
+    pub trace_deps: TraceDeps,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            output: OutputMode::Raw,
+            jobs: 1,
+            reporter: Arc::new(TerminalReporter::default()),
+            profile: None,
+            schedule: Schedule::Plan,
+            cache: None,
+            hash: HashAlgorithm::default(),
+            staleness: Staleness::default(),
+            executor: Arc::new(LocalExecutor),
+            log_dir: PathBuf::from(LOG_DIR),
+            sandbox_dir: sandbox::default_dir(),
+            trace_deps: TraceDeps::Off,
+        }
+    }
+}
+
+/// How many last lines of the log are shown when a target fails.
+pub(crate) const LOG_TAIL_LINES: usize = 20;
+
+/// Colors of targets' labels in the prefixed and grouped output.
+const LABEL_COLORS: [u8; 6] = [2, 3, 4, 5, 6, 14];
+
+/// How often the status line is redrawn while targets are running.
+const STATUS_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Builds the targets, up to `options.jobs` at a time. A target is started
+/// once all the targets it depends on have been built. If a target fails,
+/// no new targets are started and the running ones are allowed to finish.
+/// The same goes for `cancel` being set, except that the running targets
+/// are cancelled too. Finally, a summary of the build is printed.
+pub fn run_targets(
+    mut plan: Plan,
+    state: &mut BuildState,
+    options: &RunOptions,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let started = Instant::now();
+    let reporter = options.reporter.as_ref();
+
+    if options.schedule == Schedule::CriticalPath {
+        prioritize_critical_path(&mut plan.targets, state);
+    }
+
+    let mut refreshed = false;
+    for target in &plan.up_to_date {
+        refreshed |= state.refresh(target, &plan.dep_states);
+    }
+    if refreshed {
+        state.save()?;
+    }
+
+    reporter.report(&Event::plan(&plan)?)?;
+    for target in &plan.up_to_date {
+        reporter.report(&Event::TargetSkipped {
+            target: target.clone(),
+            reason: "up to date".to_string(),
+        })?;
+    }
+
+    let targets_with_contexts = plan
+        .targets
         .into_iter()
-        .map(|target| {
-            let current_dir = env::current_dir().map_err(Error::IO)?;
-            let working_dir = target.working_dir.clone().unwrap_or(current_dir);
-
-            let target_filename = target
-                .identifier
-                .file_name()
-                .ok_or(UserError::EmptyTargetIdentifier)?
-                .to_str()
-                .ok_or_else(|| Error::internal(line!(), file!()))?
-                .to_string();
-            let context = TaskContext { target_filename };
+        .map(|(target, _reason)| {
+            let working_dir = target.resolve_working_dir()?;
+            let context = target.task_context()?;
 
             Ok((target, context, working_dir))
         })
         .collect::<Result<Vec<_>>>()?;
 
-    targets_with_contexts
-        .clone()
+    let planned = targets_with_contexts
+        .iter()
+        .map(|(target, ..)| target.identifier.clone())
+        .collect::<HashSet<_>>();
+    let output = options.output;
+    let label_width = match output {
+        OutputMode::Prefixed => planned
+            .iter()
+            .map(|id| id.to_string_lossy().chars().count())
+            .max()
+            .unwrap_or(0),
+        _ => 0,
+    };
+    let mut pending = targets_with_contexts
         .into_iter()
-        .map(|(target, context, _)| {
-            target
-                .tasks
-                .into_iter()
-                .map(move |task| task.format_command(&context))
-        })
-        .flatten()
-        .map_item(|(command, args)| {
-            let text = once(command).chain(args).collect::<Vec<_>>().join(" ");
-            println!("{}", text);
+        .enumerate()
+        .map(|(ix, (target, context, working_dir))| {
+            let color = Fixed(LABEL_COLORS[ix % LABEL_COLORS.len()]);
+            let label = format!(
+                "{:width$}",
+                target.identifier.to_string_lossy(),
+                width = label_width
+            );
+            let log = TargetLog::create(
+                &options.log_dir,
+                &target.identifier,
+                output,
+                color.paint(label).to_string(),
+            )?;
+
+            Ok((target, context, working_dir, log))
         })
-        .collect::<Result<()>>()?;
+        .collect::<Result<VecDeque<_>>>()?;
 
-    targets_with_contexts
-        .into_iter()
-        .map(|(target, context, working_dir)| {
-            target
-                .tasks
-                .into_iter()
-                .map(move |task| task.run(working_dir.clone(), &context))
-                .map_item(|_| ())
+    let mut built = HashSet::new();
+    let mut first_error = None;
+    let profiler = Profiler::new(options.profile.is_some());
+    let profiler = &profiler;
+    // the ids of idle workers, each running target occupies one
+    let mut idle_workers = (0..options.jobs).collect::<BTreeSet<_>>();
+
+    thread::scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let mut running = 0;
+
+        loop {
+            // start the targets whose dependencies have been built
+            while first_error.is_none() && !idle_workers.is_empty() {
+                if cancel.load(Ordering::SeqCst) && !pending.is_empty() {
+                    first_error = Some(Error::Cancelled);
+                    break;
+                }
+
+                let ready = pending.iter().position(|(target, ..)| {
+                    target.all_deps().all(|dep| {
+                        !planned.contains(dep) || built.contains(dep)
+                    })
+                });
+                let (target, context, working_dir, log) =
+                    match ready.and_then(|ix| pending.remove(ix)) {
+                        Some(ready) => ready,
+                        None => break,
+                    };
+                let sender = sender.clone();
+                let worker = idle_workers
+                    .pop_first()
+                    .ok_or_else(|| Error::internal(line!(), file!()))?;
+                reporter.report(&Event::TargetStarted {
+                    target: target.identifier.clone(),
+                })?;
+
+                scope.spawn(move || {
+                    let profiler = profiler.worker(worker);
+                    let started = Instant::now();
+                    // a panic fails the target, otherwise the build
+                    // would wait for it forever
+                    let built = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let result = run_target(
+                            &target,
+                            &context,
+                            &working_dir,
+                            options,
+                            &log,
+                            worker,
+                            profiler,
+                            cancel,
+                        );
+                        // read here so that a missing depfile fails the target,
+                        // a restored output comes without its depfile though
+                        let mut discovered_deps =
+                            target.discovered_deps.clone();
+                        let result = result.and_then(|(outcome, traced)| {
+                            // untraced builds keep the recorded inputs
+                            if outcome == Outcome::Built
+                                && (target.depfile.is_some()
+                                    || options.trace_deps == TraceDeps::Record)
+                            {
+                                discovered_deps =
+                                    target.read_depfile(&context)?;
+                                discovered_deps.extend(traced);
+                            }
+
+                            Ok(outcome)
+                        });
+                        let recorded = profiler.record(
+                            target.identifier.to_string_lossy().to_string(),
+                            "target",
+                            started,
+                            json!({ "outcome": Outcome::of(&result) }),
+                        );
+                        let result = result
+                            .and_then(|outcome| recorded.map(|()| outcome));
+
+                        (result, discovered_deps)
+                    }));
+                    let (result, discovered_deps) =
+                        built.unwrap_or_else(|panic| {
+                            (Err(Error::panicked(panic)), vec![])
+                        });
+                    let duration = started.elapsed();
+
+                    // the receiver outlives all the workers
+                    let _ = sender.send((
+                        target,
+                        worker,
+                        log,
+                        result,
+                        discovered_deps,
+                        duration,
+                    ));
+                });
+                running += 1;
+            }
+
+            if running == 0 {
+                return Ok(());
+            }
+
+            let (target, worker, log, result, discovered_deps, duration) =
+                match receiver.recv_timeout(STATUS_REFRESH_INTERVAL) {
+                    Ok(finished) => finished,
+                    Err(RecvTimeoutError::Timeout) => {
+                        reporter.refresh();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) =>
+                        return Err(Error::internal(line!(), file!())),
+                };
+            running -= 1;
+            idle_workers.insert(worker);
+            reporter.report(&Event::TargetFinished {
+                target: target.identifier.clone(),
+                outcome: Outcome::of(&result),
+                duration_ms: event::duration_ms(duration),
+                log: log.path().to_path_buf(),
+            })?;
+
+            match result {
+                Ok(_) => {
+                    // record the state after each target so that
+                    // a failure doesn't discard the progress made so far
+                    state.record(
+                        &target.identifier,
+                        target.deps.iter().chain(&target.dynamic_deps),
+                        discovered_deps,
+                        duration,
+                    )?;
+                    state.save()?;
+                    built.insert(target.identifier);
+                }
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+    })?;
+
+    // the sandboxes have been removed by now
+    let _ = fs::remove_dir(&options.sandbox_dir);
+
+    if let Some(path) = &options.profile {
+        profiler.save(path, options.jobs)?;
+    }
+
+    reporter.report(&Event::BuildFinished {
+        success: first_error.is_none(),
+        duration_ms: event::duration_ms(started.elapsed()),
+        error: first_error.as_ref().map(ToString::to_string),
+    })?;
+
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Sorts the targets by the total recorded duration of the heaviest
+/// chain of planned targets depending on them (including themselves),
+/// in decreasing order.
+fn prioritize_critical_path(
+    targets: &mut [(Target, Reason)],
+    state: &BuildState,
+) {
+    let mut priorities = HashMap::<PathBuf, Duration>::new();
+
+    // dependants come after their dependencies in the plan
+    for (target, _) in targets.iter().rev() {
+        let duration = state
+            .target(&target.identifier)
+            .and_then(|target_state| target_state.duration)
+            .unwrap_or_default();
+        let heaviest_dependant = targets
+            .iter()
+            .filter(|(dependant, _)| {
+                dependant.all_deps().any(|dep| *dep == target.identifier)
+            })
+            .filter_map(|(dependant, _)| priorities.get(&dependant.identifier))
+            .max()
+            .copied()
+            .unwrap_or_default();
+
+        priorities
+            .insert(target.identifier.clone(), duration + heaviest_dependant);
+    }
+
+    targets.sort_by_key(|(target, _)| Reverse(priorities[&target.identifier]));
+}
+
+/// Restores target's output from the cache or runs its tasks (in worker's
+/// sandbox if the target is sandboxed). Returns the undeclared inputs
+/// found by tracing the tasks, if they should be recorded.
+#[allow(clippy::too_many_arguments)]
+fn run_target(
+    target: &Target,
+    context: &TaskContext,
+    working_dir: &Path,
+    options: &RunOptions,
+    log: &TargetLog,
+    worker: usize,
+    profiler: WorkerProfiler<'_>,
+    cancel: &AtomicBool,
+) -> Result<(Outcome, Vec<PathBuf>)> {
+    let cache = match &options.cache {
+        Some(cache) => cache.key(target, context)?.map(|key| (cache, key)),
+        None => None,
+    };
+
+    if let Some((cache, key)) = &cache {
+        if cache.restore(key, &target.identifier)? {
+            log.write_header(&format!("restored from cache ({})", key))?;
+
+            return Ok((Outcome::Cached, vec![]));
+        }
+    }
+
+    let project_dir = env::current_dir().map_err(Error::IO)?;
+    let sandbox = match target.sandbox {
+        true => Some(Sandbox::create(
+            project_dir
+                .join(&options.sandbox_dir)
+                .join(worker.to_string()),
+            &project_dir,
+            target,
+            context,
+        )?),
+        false => None,
+    };
+    let working_dir = match &sandbox {
+        Some(sandbox) => sandbox.working_dir(target),
+        None => working_dir.to_path_buf(),
+    };
+    let isolation = match target.isolated {
+        true => Some(Isolation::new(
+            &project_dir,
+            target,
+            context,
+            sandbox.as_ref().map(Sandbox::root),
+        )?),
+        false => None,
+    };
+
+    let result = target
+        .tasks
+        .iter()
+        .try_for_each(|task| {
+            run_task(
+                task,
+                target,
+                context,
+                &working_dir,
+                isolation.as_ref(),
+                options,
+                log,
+                profiler,
+                cancel,
+            )
         })
-        .flatten()
-        .collect::<Result<_>>()?;
+        .map_err(|err| match err {
+            Error::TaskFailed { .. } | Error::TimedOut { .. } =>
+                Error::TargetFailed {
+                    target: target.identifier.clone(),
+                    log: log.path().to_path_buf(),
+                    log_tail: log.tail(LOG_TAIL_LINES).unwrap_or_default(),
+                    cause: Box::new(err),
+                },
+            err => err,
+        });
 
-    Ok(())
+    if let Some(isolation) = &isolation {
+        isolation.remove_placeholders();
+    }
+    log.finish()?;
+    result?;
+
+    // the tasks have accessed the files in the sandbox
+    let root = sandbox
+        .as_ref()
+        .map_or(project_dir.as_path(), Sandbox::root);
+    let traced = check_file_accesses(target, context, options, log, root)?;
+    if let Some(sandbox) = &sandbox {
+        sandbox.finish()?;
+    }
+
+    if let Some((cache, key)) = &cache {
+        cache.store(key, &target.identifier)?;
+    }
+
+    Ok((Outcome::Built, traced))
 }
 
-pub struct MapOk<I, O> {
-    inner: I,
-    op: O,
+/// Reports the files target's tasks have accessed without the target
+/// declaring them. Returns the undeclared inputs if they should
+/// be recorded.
+fn check_file_accesses(
+    target: &Target,
+    context: &TaskContext,
+    options: &RunOptions,
+    log: &TargetLog,
+    project_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let accesses = match options.executor.take_file_accesses(log) {
+        Some(accesses) if options.trace_deps != TraceDeps::Off => accesses,
+        _ => return Ok(vec![]),
+    };
+
+    let depfile = target.depfile_path(context)?;
+    let ignored = once(options.log_dir.as_path())
+        .chain(depfile.as_deref())
+        .collect::<Vec<_>>();
+    let undeclared =
+        trace::undeclared(target, &accesses, project_dir, &ignored);
+
+    if !undeclared.is_empty() {
+        options.reporter.report(&Event::UndeclaredAccesses {
+            target: target.identifier.clone(),
+            inputs: undeclared.inputs.clone(),
+            outputs: undeclared.outputs,
+        })?;
+    }
+
+    match options.trace_deps {
+        TraceDeps::Record => Ok(undeclared.inputs),
+        _ => Ok(vec![]),
+    }
 }
 
-impl<I, T, E, O, U> Iterator for MapOk<I, O>
-where
-    I: Iterator<Item = StdResult<T, E>>,
-    O: FnMut(T) -> U,
-{
-    type Item = StdResult<U, E>;
+/// Runs the task, retrying it if it fails or times out
+/// (as many times as its `retries` allow).
+#[allow(clippy::too_many_arguments)]
+fn run_task(
+    task: &Task,
+    target: &Target,
+    context: &TaskContext,
+    working_dir: &Path,
+    isolation: Option<&Isolation>,
+    options: &RunOptions,
+    log: &TargetLog,
+    profiler: WorkerProfiler<'_>,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let reporter = options.reporter.as_ref();
+    let timeout = task.timeout.or(target.timeout).or(options.timeout);
+    let attempts = task.retries + 1;
+    let (command, args) = task.format_command(context)?;
+    let argv = once(command).chain(args).collect::<Vec<_>>();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|result| result.map(&mut self.op))
+    for attempt in 1..=attempts {
+        reporter.report(&Event::TaskStarted {
+            target: target.identifier.clone(),
+            argv: argv.clone(),
+            cwd: task.resolve_working_dir(working_dir.to_path_buf()),
+            attempt,
+        })?;
+
+        let started = Instant::now();
+        let result = task.run(
+            options.executor.as_ref(),
+            working_dir.to_path_buf(),
+            &target.env,
+            isolation,
+            context,
+            timeout,
+            log,
+            cancel,
+        );
+
+        let exit_code = result.as_ref().ok().and_then(|status| status.code());
+        profiler.record(
+            argv.join(" "),
+            "task",
+            started,
+            json!({
+                "target": target.identifier,
+                "attempt": attempt,
+                "exit_code": exit_code,
+            }),
+        )?;
+
+        reporter.report(&Event::TaskFinished {
+            target: target.identifier.clone(),
+            argv: argv.clone(),
+            attempt,
+            exit_code,
+            duration_ms: event::duration_ms(started.elapsed()),
+            error: result.as_ref().err().map(ToString::to_string),
+        })?;
+
+        let result = result.and_then(|status| {
+            if status.success() {
+                Ok(())
+            } else {
+                Err(Error::TaskFailed {
+                    command: task.command.clone(),
+                    status,
+                    attempts: attempt,
+                })
+            }
+        });
+
+        let failure = match result {
+            Ok(()) => return Ok(()),
+            Err(Error::TaskFailed { status, .. }) if attempt < attempts =>
+                status.to_string(),
+            Err(Error::TimedOut { timeout, .. }) if attempt < attempts =>
+                format!("timed out after {:?}", timeout),
+            Err(err) => return Err(err),
+        };
+
+        let delay = task.backoff_delay(attempt);
+        reporter.report(&Event::TaskRetrying {
+            target: target.identifier.clone(),
+            argv: argv.clone(),
+            attempt,
+            attempts,
+            failure,
+            delay_ms: event::duration_ms(delay),
+        })?;
+
+        sleep_unless_cancelled(delay, cancel)?;
     }
+
+    Err(Error::internal(line!(), file!()))
 }
 
-pub struct MapErr<I, O> {
+fn sleep_unless_cancelled(
+    duration: Duration,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let started = Instant::now();
+
+    while started.elapsed() < duration {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+
+        thread::sleep(Duration::from_millis(10).min(duration));
+    }
+
+    Ok(())
+}
+
+pub struct MapOk<I, O> {
     inner: I,
     op: O,
 }
 
-impl<I, T, E, O, F> Iterator for MapErr<I, O>
+impl<I, T, E, O, U> Iterator for MapOk<I, O>
 where
     I: Iterator<Item = StdResult<T, E>>,
-    O: FnMut(E) -> F,
+    O: FnMut(T) -> U,
 {
-    type Item = StdResult<T, F>;
+    type Item = StdResult<U, E>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next().map(|result| result.map_err(&mut self.op))
+        self.inner.next().map(|result| result.map(&mut self.op))
     }
 }
 
@@ -203,16 +905,229 @@ pub trait ResultIterator<T, E>:
     {
         MapOk { inner: self, op }
     }
-
-    fn map_item_err<O, F>(self, op: O) -> MapErr<Self, O>
-    where
-        O: FnMut(E) -> F,
-    {
-        MapErr { inner: self, op }
-    }
 }
 
 impl<I, T, E> ResultIterator<T, E> for I where
     I: Iterator<Item = StdResult<T, E>>
 {
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        deps::DependencyGraph,
+        executor::MockExecutor,
+        report::SilentReporter,
+        test_util::TempDir,
+    };
+
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("30"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("10 days"), None);
+    }
+
+    fn build(
+        name: &str,
+        targets: &[(&str, &[&str], &[&str])],
+        target: &str,
+        executor: Arc<MockExecutor>,
+    ) -> Result<()> {
+        build_cancellable(
+            name,
+            targets,
+            target,
+            executor,
+            &AtomicBool::new(false),
+        )
+    }
+
+    fn build_cancellable(
+        name: &str,
+        targets: &[(&str, &[&str], &[&str])],
+        target: &str,
+        executor: Arc<MockExecutor>,
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        let dir = TempDir::new(name);
+        let targets = targets
+            .iter()
+            .map(|(id, deps, commands)| Target {
+                identifier: id.into(),
+                deps: deps.iter().map(PathBuf::from).collect(),
+                tasks: commands
+                    .iter()
+                    .map(|command| Task {
+                        command: command.to_string(),
+                        retries: 1,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut state = BuildState::load(
+            dir.join("dodo.lock"),
+            HashAlgorithm::default(),
+            Staleness::default(),
+        )?;
+        let plan = DependencyGraph::construct(targets)?
+            .get_plan(target.into(), &state)?;
+        let options = RunOptions {
+            output: OutputMode::Quiet,
+            reporter: Arc::new(SilentReporter),
+            executor,
+            log_dir: dir.join("logs"),
+            ..RunOptions::default()
+        };
+
+        run_targets(plan, &mut state, &options, cancel)
+    }
+
+    fn argvs(executor: &MockExecutor) -> Vec<String> {
+        executor
+            .tasks()
+            .iter()
+            .map(|task| task.argv.join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_targets() {
+        let dir = TempDir::new("clean");
+        for file in ["out/a", "out/dir/c", "logs/a.log", "sandbox/0/x"] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+        let targets = ["out/a", "out/b", "out/dir"]
+            .iter()
+            .map(|id| Target {
+                identifier: dir.join(id),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let targets = targets.iter().collect::<Vec<_>>();
+        let others = [dir.join("logs"), dir.join("sandbox"), dir.join("none")];
+        let lock_file = dir.join("dodo.lock");
+        let load = || {
+            BuildState::load(
+                &lock_file,
+                HashAlgorithm::default(),
+                Staleness::default(),
+            )
+            .unwrap()
+        };
+        let mut state = load();
+        state
+            .record(&targets[0].identifier, &[], vec![], Duration::ZERO)
+            .unwrap();
+        state.save().unwrap();
+        let exists = |path: &str| dir.join(path).exists();
+
+        // a dry run leaves everything in place
+        clean_targets(&targets, &others, &mut load(), true).unwrap();
+        let kept = ["out/a", "out/dir/c", "logs", "sandbox"]
+            .iter()
+            .all(|path| exists(path));
+        let recorded = load().target(&targets[0].identifier).is_some();
+
+        // missing outputs and directories are skipped
+        let cleaned = clean_targets(&targets, &others, &mut load(), false);
+        let removed = ["out/a", "logs", "sandbox"]
+            .iter()
+            .all(|path| !exists(path));
+        let forgotten = load().target(&targets[0].identifier).is_none();
+        let dir_kept = exists("out/dir/c");
+
+        assert!(kept);
+        assert!(recorded);
+        cleaned.unwrap();
+        assert!(removed);
+        assert!(forgotten);
+        assert!(dir_kept);
+    }
+
+    #[test]
+    fn test_run_targets() {
+        let targets: &[(&str, &[&str], &[&str])] = &[
+            ("c", &["b", "a"], &["link {target_filename}"]),
+            ("b", &["a"], &["compile b", "strip b"]),
+            ("a", &[], &["generate a"]),
+        ];
+
+        // targets are run after their deps, tasks in order
+        let executor = Arc::new(MockExecutor::default());
+        build("run", targets, "c", executor.clone()).unwrap();
+        assert_eq!(
+            argvs(&executor),
+            vec!["generate a", "compile b", "strip b", "link c"]
+        );
+
+        // a failed task fails the target and its dependants aren't run
+        let executor = Arc::new(MockExecutor::new(|task| {
+            if task.argv[0] == "compile" {
+                1
+            } else {
+                0
+            }
+        }));
+        let result = build("fail", targets, "c", executor.clone());
+        assert!(matches!(result, Err(Error::TargetFailed { .. })));
+        // the task is retried once
+        assert_eq!(
+            argvs(&executor),
+            vec!["generate a", "compile b", "compile b"]
+        );
+
+        // a task succeeding on a retry doesn't fail the target
+        let attempts = AtomicUsize::new(0);
+        let executor = Arc::new(MockExecutor::new(move |_| {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => 1,
+                _ => 0,
+            }
+        }));
+        build("retry", targets, "a", executor.clone()).unwrap();
+        assert_eq!(argvs(&executor), vec!["generate a", "generate a"]);
+
+        // a panicking worker fails its target instead of hanging the build
+        let executor = Arc::new(MockExecutor::new(|task| {
+            if task.argv[0] == "compile" {
+                panic!("compile panicked");
+            }
+            0
+        }));
+        let result = build("panic", targets, "c", executor.clone());
+        assert!(matches!(result, Err(Error::Panicked(_))));
+        assert_eq!(argvs(&executor), vec!["generate a"]);
+
+        // no targets are started once the build is cancelled
+        let cancel = Arc::new(AtomicBool::new(false));
+        let executor = Arc::new(MockExecutor::new({
+            let cancel = cancel.clone();
+            move |_| {
+                cancel.store(true, Ordering::SeqCst);
+                0
+            }
+        }));
+        let result = build_cancellable(
+            "cancel",
+            targets,
+            "c",
+            executor.clone(),
+            &cancel,
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(argvs(&executor), vec!["generate a"]);
+    }
+}
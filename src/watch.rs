@@ -0,0 +1,273 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    build::Build,
+    error::{Error, Result},
+    event::Event,
+    interrupt,
+    report::Reporter,
+    target::CONFIG_FILE,
+    util::RunOptions,
+};
+
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+
+/// A burst of events is considered over after this much time
+/// has passed without any new events.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(100);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Builds the target and rebuilds it each time its inputs
+/// (or the config) change. With `restart` set, the running
+/// tasks are killed as soon as a change is detected, otherwise
+/// the current build is allowed to finish first.
+pub fn watch(
+    target: PathBuf,
+    restart: bool,
+    options: &RunOptions,
+) -> Result<()> {
+    let reporter = options.reporter.as_ref();
+
+    loop {
+        let mut watcher = Watcher::new()?;
+        watcher.add(CONFIG_FILE.as_ref())?;
+
+        // if the config is invalid only the config itself is watched
+        let mut running = match prepare(&target, options, &mut watcher) {
+            Ok(build) => Some(Running::spawn(build, target.clone())),
+            Err(err) => {
+                report(reporter, Err(err))?;
+                None
+            }
+        };
+
+        while !watcher.has_changed()? {
+            if interrupt::is_interrupted() {
+                if let Some(running) = running {
+                    running.cancel();
+                    report(reporter, running.wait()?.1)?;
+                }
+
+                return Ok(());
+            }
+
+            running = match running.take() {
+                Some(finished) if finished.is_finished() => {
+                    let (build, result) = finished.join()?;
+                    report(reporter, result)?;
+                    // the discover tasks may have found new deps
+                    watch_deps(&build, &target, &mut watcher)?;
+                    None
+                }
+                running => running,
+            };
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        if let Some(running) = running {
+            if restart {
+                reporter.report(&Event::Restarting)?;
+                running.cancel();
+            }
+
+            // Ctrl-C cancels the build and stops watching
+            report(reporter, running.wait()?.1)?;
+            if interrupt::is_interrupted() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Loads the build and watches the files the target depends on.
+fn prepare(
+    target: &Path,
+    options: &RunOptions,
+    watcher: &mut Watcher,
+) -> Result<Build> {
+    // the deps discovered during the previous build get watched
+    // as well, so they are updated once something changes
+    let build = Build::load(options.clone())?;
+    watch_deps(&build, target, watcher)?;
+
+    Ok(build)
+}
+
+fn watch_deps(
+    build: &Build,
+    target: &Path,
+    watcher: &mut Watcher,
+) -> Result<()> {
+    build
+        .graph()
+        .get_leaf_dependencies(target.to_path_buf())?
+        .iter()
+        .try_for_each(|leaf| watcher.add(leaf))
+}
+
+fn report(reporter: &dyn Reporter, result: Result<()>) -> Result<()> {
+    reporter.report(&Event::WatchedBuildFinished {
+        cancelled: matches!(result, Err(Error::Cancelled)),
+        error: match result {
+            Err(Error::Cancelled) | Ok(()) => None,
+            Err(err) => Some(err.to_string()),
+        },
+    })?;
+
+    reporter.report(&Event::WaitingForChanges)
+}
+
+/// A build running in its own thread.
+struct Running {
+    handle: JoinHandle<(Build, Result<()>)>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Running {
+    fn spawn(mut build: Build, target: PathBuf) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let cancel = cancel.clone();
+
+            thread::spawn(move || {
+                let result = build.run(target, &cancel);
+
+                (build, result)
+            })
+        };
+
+        Self { handle, cancel }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for the build to finish, cancelling it on Ctrl-C.
+    fn wait(self) -> Result<(Build, Result<()>)> {
+        while !self.is_finished() {
+            if interrupt::is_interrupted() {
+                self.cancel();
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        self.join()
+    }
+
+    fn join(self) -> Result<(Build, Result<()>)> {
+        self.handle
+            .join()
+            .map_err(|_| Error::internal(line!(), file!()))
+    }
+}
+
+struct Watcher {
+    inotify: Inotify,
+    /// Watched dirs and the names of the files inside them
+    /// that are of interest (`None` means all of them).
+    dirs: HashMap<WatchDescriptor, Option<HashSet<OsString>>>,
+    buffer: [u8; 4096],
+}
+
+impl Watcher {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            inotify: Inotify::init().map_err(Error::IO)?,
+            dirs: HashMap::new(),
+            buffer: [0; 4096],
+        })
+    }
+
+    fn add(&mut self, path: &Path) -> Result<()> {
+        let mask = WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+
+        if path.is_dir() {
+            let wd =
+                self.inotify.watches().add(path, mask).map_err(Error::IO)?;
+            self.dirs.insert(wd, None);
+
+            return Ok(());
+        }
+
+        // editors often replace files instead of modifying them,
+        // so the parent dir is watched instead of the file itself
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => return Ok(()),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => ".".as_ref(),
+        };
+
+        let wd = self.inotify.watches().add(dir, mask).map_err(Error::IO)?;
+        if let Some(names) =
+            self.dirs.entry(wd).or_insert_with(|| Some(HashSet::new()))
+        {
+            names.insert(name);
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether any of the watched files has changed. If so,
+    /// waits until the burst of events is over.
+    fn has_changed(&mut self) -> Result<bool> {
+        if !self.read_changes()? {
+            return Ok(false);
+        }
+
+        thread::sleep(DEBOUNCE_DELAY);
+        while self.read_changes()? {
+            thread::sleep(DEBOUNCE_DELAY);
+        }
+
+        Ok(true)
+    }
+
+    /// Reads all pending events without blocking.
+    fn read_changes(&mut self) -> Result<bool> {
+        let mut has_changed = false;
+
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock =>
+                    return Ok(has_changed),
+                Err(err) => return Err(Error::IO(err)),
+            };
+
+            for event in events {
+                has_changed |= match self.dirs.get(&event.wd) {
+                    Some(None) => true,
+                    Some(Some(names)) =>
+                        event.name.is_some_and(|name| names.contains(name)),
+                    None => false,
+                };
+            }
+        }
+    }
+}